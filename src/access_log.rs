@@ -0,0 +1,114 @@
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::status::ConnectionPath;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    ClientEof,
+    UpstreamEof,
+    ReadError,
+    WriteError,
+    /// Nothing was sent for `idle_timeout_secs`, or keepalive probes or TCP_USER_TIMEOUT ran
+    /// out.
+    Timeout,
+    /// Still open when the proxy's `drain_timeout_secs` ran out.
+    Drain,
+    UpstreamConnectError,
+    /// Not proxied, as the connection's original destination could not be found or is not
+    /// intercepted by the mapping. `upstream` is where the connection arrived.
+    NotIntercepted,
+}
+
+/// One line of the access log, written when a connection finishes.
+#[derive(Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    pub mapping: String,
+    pub client: SocketAddr,
+    pub local_bind: SocketAddrV4,
+    pub upstream: SocketAddr,
+    pub path: ConnectionPath,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration_secs: f64,
+    pub termination: TerminationReason,
+}
+
+impl AccessLogEntry {
+    pub fn timestamp_now() -> String {
+        humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+    }
+}
+
+/// Where access log lines are written; `-` means stdout.
+#[derive(Clone, Debug)]
+pub enum Destination {
+    Stdout,
+    File(PathBuf),
+}
+
+impl From<&str> for Destination {
+    fn from(value: &str) -> Self {
+        match value {
+            "-" => Destination::Stdout,
+            path => Destination::File(PathBuf::from(path)),
+        }
+    }
+}
+
+static SENDER: OnceLock<mpsc::Sender<String>> = OnceLock::new();
+
+/// Starts the access log writer. Until this is called, `record` discards entries.
+pub async fn init(destination: Destination) -> anyhow::Result<()> {
+    let mut writer: Box<dyn tokio::io::AsyncWrite + Send + Unpin> = match &destination {
+        Destination::Stdout => Box::new(tokio::io::stdout()),
+        Destination::File(path) => Box::new(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?,
+        ),
+    };
+    let (tx, mut rx) = mpsc::channel::<String>(4096);
+    if SENDER.set(tx).is_err() {
+        anyhow::bail!("access log already initialised");
+    }
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            // write whatever else has queued up meanwhile before flushing
+            let mut next = Some(line);
+            while let Some(line) = next {
+                if let Err(e) = writer.write_all(line.as_bytes()).await {
+                    tracing::warn!("Error writing access log to {:?}: {:?}", destination, e);
+                }
+                next = rx.try_recv().ok();
+            }
+            writer.flush().await.ok();
+        }
+    });
+    Ok(())
+}
+
+pub fn record(entry: &AccessLogEntry) {
+    let Some(sender) = SENDER.get() else {
+        return;
+    };
+    match serde_json::to_string(entry) {
+        Ok(mut line) => {
+            line.push('\n');
+            if sender.try_send(line).is_err() {
+                tracing::warn!("access log writer is falling behind, dropping entry");
+            }
+        }
+        Err(e) => tracing::warn!("Error serialising access log entry: {:?}", e),
+    }
+}
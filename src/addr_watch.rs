@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        use futures::stream::StreamExt;
+        use netlink_packet_route::{RTM_DELADDR, RTM_NEWADDR};
+        use netlink_sys::{AsyncSocket, SocketAddr};
+        use rtnetlink::constants::RTMGRP_IPV4_IFADDR;
+    } else {
+    }
+}
+
+/// Subscribes to IPv4 addresses being added to or removed from interfaces. The returned
+/// `Notify` is signalled on every change, so bind addresses can be re-resolved straight away
+/// instead of at the next poll.
+#[cfg(target_os = "linux")]
+pub fn watch() -> anyhow::Result<Arc<Notify>> {
+    let (mut connection, _handle, mut messages) = rtnetlink::new_connection()?;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, RTMGRP_IPV4_IFADDR))?;
+    tokio::spawn(connection);
+
+    let changed = Arc::new(Notify::new());
+    let notify = changed.clone();
+    tokio::spawn(async move {
+        while let Some((message, _)) = messages.next().await {
+            let message_type = message.header.message_type;
+            if message_type == RTM_NEWADDR || message_type == RTM_DELADDR {
+                tracing::debug!(
+                    "interface address {}",
+                    if message_type == RTM_NEWADDR {
+                        "added"
+                    } else {
+                        "removed"
+                    }
+                );
+                notify.notify_one();
+            }
+        }
+        tracing::warn!("interface address subscription closed");
+    });
+    Ok(changed)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch() -> anyhow::Result<Arc<Notify>> {
+    anyhow::bail!("watching interface addresses is only supported on Linux");
+}
@@ -1,103 +1,591 @@
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::config::IntMapping;
-use crate::tcp_helper;
+use crate::access_log::{self, AccessLogEntry, TerminationReason};
+use crate::config::{IntMapping, MappingMode};
+use crate::metrics::{self, metrics};
+use crate::status::{next_connection_id, ConnectionPath, ProxyState, ProxyStatus};
+use crate::{systemd, tcp_helper};
+use prometheus::IntCounter;
+use socket2::SockRef;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-async fn pipe(mut rx: OwnedReadHalf, mut tx: OwnedWriteHalf) {
+/// How one direction of a proxied connection came to an end.
+enum PipeEnd {
+    Eof,
+    ReadError,
+    WriteError,
+    TimedOut,
+}
+
+impl PipeEnd {
+    /// Why the connection ended, if this was the direction to end first. `eof` is the reason
+    /// for it closing normally.
+    fn termination(end: Result<PipeEnd, JoinError>, eof: TerminationReason) -> TerminationReason {
+        match end {
+            Ok(PipeEnd::Eof) => eof,
+            Ok(PipeEnd::WriteError) => TerminationReason::WriteError,
+            Ok(PipeEnd::TimedOut) => TerminationReason::Timeout,
+            Ok(PipeEnd::ReadError) | Err(_) => TerminationReason::ReadError,
+        }
+    }
+}
+
+async fn pipe(
+    mut rx: OwnedReadHalf,
+    mut tx: OwnedWriteHalf,
+    bytes: IntCounter,
+    transferred: Arc<AtomicU64>,
+) -> PipeEnd {
     let mut buffer = [0; 16384];
     loop {
         match rx.read(&mut buffer).await {
             Ok(n) => {
                 if n == 0 {
-                    break;
+                    return PipeEnd::Eof;
                 }
                 if let Err(e) = tx.write_all(&buffer[..n]).await {
                     tracing::info!("Error writing buffer to other stream: {:?}", e);
-                    break;
+                    if e.kind() == ErrorKind::TimedOut {
+                        return PipeEnd::TimedOut;
+                    }
+                    return PipeEnd::WriteError;
                 }
+                bytes.inc_by(n as u64);
+                transferred.fetch_add(n as u64, Ordering::Relaxed);
             }
             Err(e) => {
                 tracing::info!("Error reading from stream: {:?}", e);
-                break;
+                if e.kind() == ErrorKind::TimedOut {
+                    return PipeEnd::TimedOut;
+                }
+                return PipeEnd::ReadError;
             }
         };
     }
 }
 
-async fn proxy_connection(client: TcpStream, mapping: IntMapping) -> anyhow::Result<()> {
-    let upstream = match (
-        client.peer_addr(),
-        mapping.target_address,
-        mapping.transparent,
-    ) {
+/// Resolves once none of `counters` has moved for a whole `secs` period, or never if `secs` is
+/// unset.
+async fn idle(counters: &[&AtomicU64], secs: Option<u64>) {
+    let Some(secs) = secs else {
+        return std::future::pending().await;
+    };
+    let transferred = || -> u64 { counters.iter().map(|c| c.load(Ordering::Relaxed)).sum() };
+    let mut last = transferred();
+    loop {
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        let current = transferred();
+        if current == last {
+            return;
+        }
+        last = current;
+    }
+}
+
+/// Where to connect for `client`: the mapping's target, or for an intercepting mapping the
+/// destination the client was connecting to, as long as the mapping intercepts it.
+fn upstream_target(client: &TcpStream, mapping: &IntMapping) -> anyhow::Result<SocketAddrV4> {
+    let destination = match mapping.mode {
+        MappingMode::Proxy => {
+            return mapping
+                .target_address
+                .ok_or_else(|| anyhow::anyhow!("mapping has no target address"));
+        }
+        // TPROXY leaves the original destination as the local address
+        MappingMode::Tproxy => client.local_addr()?,
+        MappingMode::Redirect => {
+            let destination = SocketAddr::V4(
+                tcp_helper::original_dst(client)
+                    .map_err(|e| e.context("no original destination, not redirected"))?,
+            );
+            // the listener is bound on 0.0.0.0, so compare with where this connection arrived
+            if destination == client.local_addr()? {
+                anyhow::bail!("connection to {destination} was not redirected");
+            }
+            destination
+        }
+    };
+    let SocketAddr::V4(destination) = destination else {
+        anyhow::bail!("original destination {destination} is not IPv4");
+    };
+    // connections made to the listener itself would otherwise be proxied back to it
+    let intercepted = mapping
+        .destinations
+        .as_ref()
+        .is_some_and(|destinations| destinations.matches(&destination));
+    if !intercepted || destination == mapping.local_bind {
+        anyhow::bail!("destination {destination} is not intercepted by this mapping");
+    }
+    Ok(destination)
+}
+
+async fn proxy_connection(
+    id: u64,
+    client: TcpStream,
+    mapping: IntMapping,
+    status: Arc<ProxyStatus>,
+    drain: CancellationToken,
+) -> anyhow::Result<()> {
+    let labels = metrics::mapping_labels(&mapping);
+    let client_addr = client.peer_addr()?;
+    let _active = metrics::ActiveConnection::new(&labels);
+
+    let target = match upstream_target(&client, &mapping) {
+        Ok(target) => target,
+        Err(e) => {
+            tracing::info!("Not proxying connection from {client_addr}: {:#}", e);
+            metrics()
+                .connections_rejected
+                .with_label_values(&[&labels[0], &labels[1]])
+                .inc();
+            access_log::record(&AccessLogEntry {
+                timestamp: AccessLogEntry::timestamp_now(),
+                mapping: mapping.name.clone(),
+                client: client_addr,
+                local_bind: mapping.local_bind,
+                upstream: client.local_addr()?,
+                path: ConnectionPath::Direct,
+                bytes_in: 0,
+                bytes_out: 0,
+                duration_secs: 0.0,
+                termination: TerminationReason::NotIntercepted,
+            });
+            return Err(e);
+        }
+    };
+
+    // keeps upstream connections of intercepting mappings from being intercepted again
+    let direct_mark = mapping.upstream_mark.filter(|_| mapping.mode.intercepts());
+    let connect_started = Instant::now();
+    let (path, upstream) = match (client.peer_addr(), target, mapping.transparent) {
         (Ok(SocketAddr::V4(client_v4)), target_v4, true) => {
             if mapping.connection_is_hairpin(client_v4.ip()) {
-                Ok(tokio::net::TcpStream::connect(target_v4).await?)
+                (
+                    ConnectionPath::Hairpin,
+                    tcp_helper::connect(target_v4, &mapping.socket_options, direct_mark).await,
+                )
             } else {
-                tcp_helper::tcpstream_connect_from_addr(client_v4, target_v4).await
+                (
+                    ConnectionPath::Transparent,
+                    tcp_helper::tcpstream_connect_from_addr(
+                        client_v4,
+                        target_v4,
+                        &mapping.socket_options,
+                        mapping.upstream_mark,
+                    )
+                    .await,
+                )
             }
         }
-        _ => Ok(tokio::net::TcpStream::connect(SocketAddr::V4(mapping.target_address)).await?),
-    }?;
+        _ => (
+            ConnectionPath::Direct,
+            tcp_helper::connect(target, &mapping.socket_options, direct_mark).await,
+        ),
+    };
+    let upstream = match upstream {
+        Ok(upstream) => {
+            metrics()
+                .upstream_connect_seconds
+                .with_label_values(&[&labels[0], &labels[1]])
+                .observe(connect_started.elapsed().as_secs_f64());
+            upstream
+        }
+        Err(e) => {
+            tracing::info!("Error connecting to upstream {}: {:#}", target, e);
+            metrics()
+                .upstream_connect_failures
+                .with_label_values(&[&labels[0], &labels[1]])
+                .inc();
+            access_log::record(&AccessLogEntry {
+                timestamp: AccessLogEntry::timestamp_now(),
+                mapping: mapping.name.clone(),
+                client: client_addr,
+                local_bind: mapping.local_bind,
+                upstream: SocketAddr::V4(target),
+                path,
+                bytes_in: 0,
+                bytes_out: 0,
+                duration_secs: connect_started.elapsed().as_secs_f64(),
+                termination: TerminationReason::UpstreamConnectError,
+            });
+            return Err(e);
+        }
+    };
+    metrics()
+        .connections_routed
+        .with_label_values(&[&labels[0], &labels[1], path.as_str()])
+        .inc();
 
+    let tracked = status.track(id, client_addr, upstream.peer_addr()?, path);
+    let connection = tracked.connection.clone();
     let (in_rx, in_tx) = client.into_split();
     let (out_rx, out_tx) = upstream.into_split();
 
-    tokio::select! {
-        _ = tokio::spawn(pipe(in_rx, out_tx)) => (),
-        _ = tokio::spawn(pipe(out_rx, in_tx)) => (),
+    let mut client_to_upstream = tokio::spawn(
+        pipe(
+            in_rx,
+            out_tx,
+            metrics::bytes_counter(&labels, "in"),
+            connection.bytes_in.clone(),
+        )
+        .in_current_span(),
+    );
+    let mut upstream_to_client = tokio::spawn(
+        pipe(
+            out_rx,
+            in_tx,
+            metrics::bytes_counter(&labels, "out"),
+            connection.bytes_out.clone(),
+        )
+        .in_current_span(),
+    );
+
+    // the direction that ends first decides why the connection ended
+    let both = [&*connection.bytes_in, &*connection.bytes_out];
+    let (mut termination, half_closed) = tokio::select! {
+        end = &mut client_to_upstream => {
+            (PipeEnd::termination(end, TerminationReason::ClientEof), Some(true))
+        },
+        end = &mut upstream_to_client => {
+            (PipeEnd::termination(end, TerminationReason::UpstreamEof), Some(false))
+        },
+        _ = idle(&both, mapping.idle_timeout_secs) => {
+            (TerminationReason::Timeout, None)
+        },
+        _ = drain.cancelled() => (TerminationReason::Drain, None),
+    };
+    // after an EOF the other side can still send, for as long as it keeps doing so
+    let eof = matches!(
+        termination,
+        TerminationReason::ClientEof | TerminationReason::UpstreamEof
+    );
+    if let Some(client_ended) = half_closed.filter(|_| eof) {
+        let (other, transferred) = if client_ended {
+            (&mut upstream_to_client, [&*connection.bytes_out])
+        } else {
+            (&mut client_to_upstream, [&*connection.bytes_in])
+        };
+        tokio::select! {
+            _ = other => (),
+            _ = idle(&transferred, Some(mapping.half_close_timeout_secs)) => (),
+            _ = drain.cancelled() => termination = TerminationReason::Drain,
+        }
     }
+    client_to_upstream.abort();
+    upstream_to_client.abort();
+
+    access_log::record(&AccessLogEntry {
+        timestamp: AccessLogEntry::timestamp_now(),
+        mapping: mapping.name.clone(),
+        client: connection.client,
+        local_bind: mapping.local_bind,
+        upstream: connection.upstream,
+        path: connection.path,
+        bytes_in: connection.bytes_in.load(Ordering::Relaxed),
+        bytes_out: connection.bytes_out.load(Ordering::Relaxed),
+        duration_secs: connection.started.elapsed().as_secs_f64(),
+        termination,
+    });
 
     Ok(())
 }
 
+/// How long to wait before the next bind attempt, after `failures` failed ones.
+fn bind_retry_delay(mapping: &IntMapping, failures: u32) -> Duration {
+    let secs = mapping
+        .bind_retry_initial_secs
+        .saturating_mul(2u64.saturating_pow(failures))
+        .min(mapping.bind_retry_max_secs);
+    Duration::from_secs(secs)
+}
+
 pub async fn start_proxy(
     mapping: IntMapping,
-    cancel: tokio_util::sync::CancellationToken,
+    cancel: CancellationToken,
+    status: Arc<ProxyStatus>,
 ) -> anyhow::Result<()> {
     tracing::info!(
-        "staring proxy on addrs {}:{} to {}:{}. Hairpin net: {:?}",
+        "staring proxy on addrs {}:{} to {}. Hairpin net: {:?}",
         mapping.local_bind.ip(),
         mapping.local_bind.port(),
-        mapping.target_address.ip().to_string(),
-        mapping.target_address.port(),
+        mapping.target_label(),
         mapping.hairpin_net,
     );
+    let labels = metrics::mapping_labels(&mapping);
+    let drain = CancellationToken::new();
+    let mut bind_failures: u32 = 0;
     loop {
         let mut connections = tokio::task::JoinSet::new();
-        match tcp_helper::bind_reuseport(mapping.local_bind).await {
-            Ok(listener) => loop {
-                tokio::select!(
-                    _ = cancel.cancelled() => {
-                        drop(listener);
-                        // after being cancelled & dropping the listener, wait until the
-                        // current set of connections finish up before returning.
-                        while !connections.is_empty() {
-                            connections.join_next().await;
-                        }
-                        return Ok(());
-                    },
-                    accept_result = listener.accept() => {
-                        match accept_result {
-                            Ok((stream, _remote_addr)) => {
-                                connections.spawn(proxy_connection(stream, mapping));
+        let listener = match systemd::activated_listener(mapping.local_bind) {
+            Ok(Some(listener)) => Ok(listener),
+            Ok(None) => {
+                tcp_helper::bind_listener(
+                    mapping.local_bind,
+                    &mapping.socket_options,
+                    mapping.mode == MappingMode::Tproxy,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match listener {
+            Ok(listener) => {
+                if bind_failures > 0 {
+                    tracing::info!("bound after {bind_failures} failed attempts");
+                }
+                status.set_bind_error(None);
+                status.set_state(ProxyState::Bound);
+                loop {
+                    tokio::select!(
+                        _ = cancel.cancelled() => {
+                            drop(listener);
+                            status.set_state(ProxyState::Draining);
+                            // after being cancelled & dropping the listener, wait until the
+                            // current set of connections finish up before returning, closing
+                            // any still open after the drain timeout.
+                            let deadline = tokio::time::sleep(Duration::from_secs(
+                                mapping.drain_timeout_secs,
+                            ));
+                            tokio::pin!(deadline);
+                            while !connections.is_empty() {
+                                tokio::select! {
+                                    _ = connections.join_next() => (),
+                                    _ = &mut deadline, if !drain.is_cancelled() => {
+                                        tracing::info!(
+                                            "drain timeout reached, closing {} connections",
+                                            connections.len()
+                                        );
+                                        drain.cancel();
+                                    },
+                                }
                             }
-                            Err(e) => {
-                                tracing::info!("Error proxying connection: {:?}", e);
+                            return Ok(());
+                        },
+                        accept_result = listener.accept() => {
+                            match accept_result {
+                                Ok((stream, remote_addr)) => {
+                                    if let Err(e) = tcp_helper::apply_stream_options(
+                                        SockRef::from(&stream),
+                                        &mapping.socket_options,
+                                    ) {
+                                        tracing::warn!(
+                                            "failed to set socket options for {remote_addr}: {e}"
+                                        );
+                                    }
+                                    metrics()
+                                        .connections_accepted
+                                        .with_label_values(&[&labels[0], &labels[1]])
+                                        .inc();
+                                    let id = next_connection_id();
+                                    let span = tracing::info_span!(
+                                        "connection",
+                                        id,
+                                        mapping = %mapping.name,
+                                        client = %remote_addr,
+                                    );
+                                    connections.spawn(
+                                        proxy_connection(
+                                            id,
+                                            stream,
+                                            mapping.clone(),
+                                            status.clone(),
+                                            drain.clone(),
+                                        )
+                                        .instrument(span),
+                                    );
+                                }
+                                Err(e) => {
+                                    metrics()
+                                        .connections_rejected
+                                        .with_label_values(&[&labels[0], &labels[1]])
+                                        .inc();
+                                    tracing::info!("Error proxying connection: {:?}", e);
+                                }
                             }
-                        }
-                    },
-                    Some(_) = connections.join_next() => (),
-                );
-            },
+                        },
+                        Some(_) = connections.join_next() => (),
+                    );
+                }
+            }
             Err(e) => {
-                tracing::info!("failed to bind: {:?}", e);
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                metrics()
+                    .bind_failures
+                    .with_label_values(&[&labels[0], &labels[1]])
+                    .inc();
+                let delay = bind_retry_delay(&mapping, bind_failures);
+                bind_failures += 1;
+                status.set_bind_error(Some(format!("{e:#}")));
+                status.set_state(ProxyState::RetryingBind);
+                if delay.as_secs() >= mapping.bind_retry_max_secs {
+                    tracing::error!(
+                        "failed to bind {} ({bind_failures} attempts), retrying in {delay:?}: {:#}",
+                        mapping.local_bind,
+                        e
+                    );
+                } else {
+                    tracing::warn!(
+                        "failed to bind {}, retrying in {delay:?}: {:#}",
+                        mapping.local_bind,
+                        e
+                    );
+                }
+                tokio::select! {
+                    _ = cancel.cancelled() => return Ok(()),
+                    _ = tokio::time::sleep(delay) => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn local_addr(listener: &tokio::net::TcpListener) -> SocketAddrV4 {
+        match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn half_closed_connections_linger_then_drain() {
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_bind = local_addr(&tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let mapping = IntMapping {
+            name: "half-close".to_string(),
+            local_bind,
+            target_address: Some(local_addr(&target)),
+            mode: MappingMode::Proxy,
+            destinations: None,
+            transparent: false,
+            manage_iptables: false,
+            hairpin_net: None,
+            idle_timeout_secs: None,
+            half_close_timeout_secs: 1,
+            drain_timeout_secs: 1,
+            bind_retry_initial_secs: 1,
+            bind_retry_max_secs: 1,
+            socket_options: Default::default(),
+            upstream_mark: None,
+        };
+        let cancel = CancellationToken::new();
+        let status = ProxyStatus::register(local_bind, mapping.target_address, false);
+        let proxy = tokio::spawn(start_proxy(mapping, cancel.clone(), status.clone()));
+        status.bind_result(Duration::from_secs(5)).await.unwrap();
+
+        // the upstream can still answer after the client has finished sending
+        let mut client = TcpStream::connect(local_bind).await.unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(upstream.read(&mut buffer).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        upstream.write_all(b"late").await.unwrap();
+        client.read_exact(&mut buffer[..4]).await.unwrap();
+        assert_eq!(&buffer[..4], b"late");
+        // but once it goes quiet the connection is closed, though the upstream never closed
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buffer)).await;
+        assert_eq!(
+            closed
+                .expect("half-closed connection never closed")
+                .unwrap(),
+            0
+        );
+
+        // a connection still open when the proxy stops is closed once the drain timeout is up
+        let mut client = TcpStream::connect(local_bind).await.unwrap();
+        let (_upstream, _) = target.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), proxy)
+            .await
+            .expect("proxy never finished draining")
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+        status.unregister();
+    }
+
+    #[tokio::test]
+    async fn connections_not_intercepted_are_logged_and_counted() {
+        let log_path =
+            std::env::temp_dir().join(format!("stargate-access-{}.log", std::process::id()));
+        let _ = tokio::fs::remove_file(&log_path).await;
+        access_log::init(access_log::Destination::File(log_path.clone()))
+            .await
+            .unwrap();
+        let local_bind = local_addr(&tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let mapping = IntMapping {
+            name: "not-intercepted".to_string(),
+            local_bind,
+            target_address: None,
+            mode: MappingMode::Redirect,
+            destinations: Some(crate::config::DestinationFilter {
+                nets: vec!["127.0.0.0/8".parse().unwrap()],
+                ports: vec![],
+            }),
+            transparent: false,
+            manage_iptables: false,
+            hairpin_net: None,
+            idle_timeout_secs: None,
+            half_close_timeout_secs: 1,
+            drain_timeout_secs: 1,
+            bind_retry_initial_secs: 1,
+            bind_retry_max_secs: 1,
+            socket_options: Default::default(),
+            upstream_mark: None,
+        };
+        let labels = metrics::mapping_labels(&mapping);
+        let rejected = metrics()
+            .connections_rejected
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let cancel = CancellationToken::new();
+        let status = ProxyStatus::register(local_bind, None, false);
+        let proxy = tokio::spawn(start_proxy(mapping, cancel.clone(), status.clone()));
+        status.bind_result(Duration::from_secs(5)).await.unwrap();
+
+        // connecting to the listener directly leaves no original destination to proxy to
+        let mut client = TcpStream::connect(local_bind).await.unwrap();
+        let mut buffer = [0; 16];
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buffer)).await;
+        assert!(closed
+            .expect("connection never closed")
+            .map_or(true, |n| n == 0));
+        assert_eq!(rejected.get(), 1);
+
+        let mut log = String::new();
+        for _ in 0..50 {
+            log = tokio::fs::read_to_string(&log_path)
+                .await
+                .unwrap_or_default();
+            if log.contains("not-intercepted") {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        let entry: serde_json::Value = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|entry: &serde_json::Value| entry["mapping"] == "not-intercepted")
+            .unwrap_or_else(|| panic!("no entry in:\n{log}"));
+        assert_eq!(entry["termination"], "not_intercepted");
+        assert_eq!(entry["upstream"], local_bind.to_string());
+
+        cancel.cancel();
+        proxy.await.unwrap().unwrap();
+        status.unregister();
+        tokio::fs::remove_file(&log_path).await.unwrap();
     }
 }
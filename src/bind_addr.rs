@@ -1,18 +1,54 @@
 use interfaces::{InterfaceFlags, Kind};
+use ipnet::Ipv4Net;
 use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
 
-pub async fn get_bind_addresses(bind_loopback: bool) -> anyhow::Result<Vec<Ipv4Addr>> {
+/// Picks which of the host's addresses to listen on: a literal address, every address within a
+/// CIDR, or every address of a named interface.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BindSelector {
+    Addr(Ipv4Addr),
+    Net(Ipv4Net),
+    Interface(String),
+}
+
+impl FromStr for BindSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<Ipv4Addr>() {
+            return Ok(BindSelector::Addr(addr));
+        }
+        if s.contains('/') {
+            return Ok(BindSelector::Net(s.parse::<Ipv4Net>()?));
+        }
+        // IFNAMSIZ - 1
+        if s.is_empty() || s.len() > 15 || s.contains(|c: char| c.is_whitespace() || c == ':') {
+            anyhow::bail!("'{s}' is not an IPv4 address, CIDR or interface name");
+        }
+        Ok(BindSelector::Interface(s.to_string()))
+    }
+}
+
+/// An IPv4 address of an interface that is UP.
+#[derive(Debug, Clone)]
+pub struct HostAddress {
+    pub interface: String,
+    pub addr: Ipv4Addr,
+}
+
+pub fn host_addresses() -> anyhow::Result<Vec<HostAddress>> {
     let mut result = vec![];
-    let ipnet_lb = ipnet::Ipv4Net::new(Ipv4Addr::new(127, 0, 0, 0), 8).unwrap();
     let intfs = interfaces::Interface::get_all()?;
     for intf in &intfs {
         if intf.flags.contains(InterfaceFlags::IFF_UP) {
             for addr in &intf.addresses {
                 if let Kind::Ipv4 = addr.kind {
                     if let Some(IpAddr::V4(v4_addr)) = addr.addr.map(|a| a.ip()) {
-                        if bind_loopback || !ipnet_lb.contains(&v4_addr) {
-                            result.push(v4_addr);
-                        }
+                        result.push(HostAddress {
+                            interface: intf.name.clone(),
+                            addr: v4_addr,
+                        });
                     }
                 }
             }
@@ -20,3 +56,88 @@ pub async fn get_bind_addresses(bind_loopback: bool) -> anyhow::Result<Vec<Ipv4A
     }
     Ok(result)
 }
+
+/// The addresses `selectors` pick out of `host`, sorted and without duplicates. No selectors
+/// means every address, leaving out loopback ones unless `bind_loopback` is set.
+pub fn select(
+    selectors: &[BindSelector],
+    host: &[HostAddress],
+    bind_loopback: bool,
+) -> Vec<Ipv4Addr> {
+    let mut result: Vec<Ipv4Addr> = if selectors.is_empty() {
+        host.iter()
+            .map(|h| h.addr)
+            .filter(|addr| bind_loopback || !addr.is_loopback())
+            .collect()
+    } else {
+        selectors
+            .iter()
+            .flat_map(|selector| match selector {
+                BindSelector::Addr(addr) => vec![*addr],
+                BindSelector::Net(net) => host
+                    .iter()
+                    .map(|h| h.addr)
+                    .filter(|addr| net.contains(addr))
+                    .collect(),
+                BindSelector::Interface(name) => host
+                    .iter()
+                    .filter(|h| &h.interface == name)
+                    .map(|h| h.addr)
+                    .collect(),
+            })
+            .collect()
+    };
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// Like `select`, skipping selectors that don't parse.
+pub fn resolve(selectors: &[String], host: &[HostAddress], bind_loopback: bool) -> Vec<Ipv4Addr> {
+    let selectors: Vec<BindSelector> = selectors.iter().filter_map(|s| s.parse().ok()).collect();
+    select(&selectors, host, bind_loopback)
+}
+
+pub async fn get_bind_addresses(bind_loopback: bool) -> anyhow::Result<Vec<Ipv4Addr>> {
+    Ok(select(&[], &host_addresses()?, bind_loopback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        let host = [
+            ("lo", [127, 0, 0, 1]),
+            ("eth0", [10, 0, 0, 5]),
+            ("eth1", [192, 168, 1, 5]),
+            ("eth1", [192, 168, 2, 5]),
+        ]
+        .map(|(interface, addr)| HostAddress {
+            interface: interface.to_string(),
+            addr: addr.into(),
+        });
+        let select_strs = |selectors: &[&str]| -> Vec<String> {
+            let selectors: Vec<BindSelector> =
+                selectors.iter().map(|s| s.parse().unwrap()).collect();
+            select(&selectors, &host, false)
+                .iter()
+                .map(|a| a.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            select_strs(&[]),
+            vec!["10.0.0.5", "192.168.1.5", "192.168.2.5"]
+        );
+        assert_eq!(select_strs(&["eth1"]), vec!["192.168.1.5", "192.168.2.5"]);
+        assert_eq!(
+            select_strs(&["192.168.2.0/24", "127.0.0.1"]),
+            vec!["127.0.0.1", "192.168.2.5"]
+        );
+        assert_eq!(select_strs(&["10.0.0.0/8", "eth0"]), vec!["10.0.0.5"]);
+        assert!("10.0.0.0/33".parse::<BindSelector>().is_err());
+        assert!("eth 0".parse::<BindSelector>().is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::path::Path;
+
+use crate::bind_addr::{BindSelector, HostAddress};
+use crate::config::{
+    listener_taken, owned_listeners, plain_addresses, Config, ConfigDir, ConfigFormat, Fragment,
+    Mapping, MappingMode, Problem, ReturnRouting,
+};
+use serde::de::DeserializeOwned;
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        const CAP_NET_ADMIN: u32 = 12;
+        const CAP_NET_RAW: u32 = 13;
+    } else {
+    }
+}
+
+/// Loads the config file at `path` and reports every problem found with it.
+pub async fn check_file(path: &Path, format: ConfigFormat) -> Vec<Problem> {
+    let config_bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return vec![Problem::new(
+                "$".to_string(),
+                format!("cannot read {}: {e}", path.display()),
+            )]
+        }
+    };
+    let config: Config = match parse_with_path(&config_bytes, format) {
+        Ok(config) => config,
+        Err(problem) => return vec![problem],
+    };
+    let mut problems = config.problems();
+    problems.extend(host_problems(&config).await);
+    problems
+}
+
+/// Checks the base file and every fragment of a config directory, including port conflicts
+/// between them. Problem paths are prefixed with the file they were found in.
+pub async fn check_dir(dir: &Path) -> Vec<Problem> {
+    let dir = match ConfigDir::list(dir).await {
+        Ok(dir) => dir,
+        Err(e) => return vec![Problem::new("$".to_string(), e.to_string())],
+    };
+    let mut problems = vec![];
+    let base_problems = check_file(&dir.base, ConfigFormat::from_path(&dir.base)).await;
+    problems.extend(in_file(&dir.base, base_problems));
+    // compared where they listen on this host, as they would be when loaded, or failing that
+    // by the addresses known without looking at it
+    let host = crate::bind_addr::host_addresses().ok();
+    let addresses = |selectors: &[String]| match &host {
+        Some(host) => crate::bind_addr::resolve(selectors, host, false),
+        None => plain_addresses(selectors),
+    };
+    let mut bind_addrs = vec![];
+    let mut listeners = vec![];
+    if let Ok(bytes) = tokio::fs::read(&dir.base).await {
+        if let Ok(base) = parse_with_path::<Config>(&bytes, ConfigFormat::from_path(&dir.base)) {
+            bind_addrs = base.bind_addrs;
+            let owner = dir.base.display().to_string();
+            listeners = owned_listeners(&base.mappings, &owner, &bind_addrs, &addresses);
+        }
+    }
+
+    for path in &dir.fragments {
+        let fragment = match tokio::fs::read(path).await {
+            Ok(bytes) => parse_with_path::<Fragment>(&bytes, ConfigFormat::from_path(path)),
+            Err(e) => Err(Problem::new("$".to_string(), format!("cannot read: {e}"))),
+        };
+        let fragment = match fragment {
+            Ok(fragment) => fragment,
+            Err(problem) => {
+                problems.extend(in_file(path, vec![problem]));
+                continue;
+            }
+        };
+        let as_config = Config {
+            mappings: fragment.mappings.clone(),
+            ..Default::default()
+        };
+        problems.extend(in_file(path, as_config.problems()));
+        let mappings = &fragment.mappings;
+        for (i, mapping) in mappings.iter().enumerate() {
+            let mapping = std::slice::from_ref(mapping);
+            if let Some(conflict) = listener_taken(&listeners, mapping, &bind_addrs, &addresses) {
+                let problem = Problem::new(format!("$.mappings[{i}].local_port"), conflict);
+                problems.extend(in_file(path, vec![problem]));
+            }
+        }
+        let owner = path.display().to_string();
+        listeners.extend(owned_listeners(mappings, &owner, &bind_addrs, &addresses));
+    }
+    problems
+}
+
+fn in_file(path: &Path, problems: Vec<Problem>) -> Vec<Problem> {
+    problems
+        .into_iter()
+        .map(|p| Problem::new(format!("{}:{}", path.display(), p.path), p.message))
+        .collect()
+}
+
+/// Parses a config file, reporting the JSON path of the value that failed to deserialize.
+fn parse_with_path<T: DeserializeOwned>(bytes: &[u8], format: ConfigFormat) -> Result<T, Problem> {
+    fn to_problem<E: std::fmt::Display>(e: serde_path_to_error::Error<E>) -> Problem {
+        let path = match e.path().to_string().as_str() {
+            "." => "$".to_string(),
+            path => format!("$.{path}"),
+        };
+        Problem::new(path, e.into_inner().to_string())
+    }
+    match format {
+        ConfigFormat::Json => {
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(bytes))
+                .map_err(to_problem)
+        }
+        ConfigFormat::Toml => {
+            let text = std::str::from_utf8(bytes)
+                .map_err(|e| Problem::new("$".to_string(), e.to_string()))?;
+            serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(to_problem)
+        }
+        ConfigFormat::Yaml => {
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_slice(bytes))
+                .map_err(to_problem)
+        }
+    }
+}
+
+/// Problems with running `config` on this host: bind addresses it doesn't have, and
+/// transparent or intercepting mappings without the capabilities they need.
+async fn host_problems(config: &Config) -> Vec<Problem> {
+    let mut problems = vec![];
+    match crate::bind_addr::host_addresses() {
+        Ok(host) => {
+            let selectors = config
+                .bind_addrs
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (format!("$.bind_addrs[{i}]"), s))
+                .chain(config.mappings.iter().enumerate().flat_map(|(i, m)| {
+                    m.bind_addrs
+                        .iter()
+                        .flatten()
+                        .enumerate()
+                        .map(move |(j, s)| (format!("$.mappings[{i}].bind_addrs[{j}]"), s))
+                }));
+            for (path, selector) in selectors {
+                if let Ok(selector) = selector.parse::<BindSelector>() {
+                    if let Some(message) = selector_problem(&selector, &host) {
+                        problems.push(Problem::new(path, message));
+                    }
+                }
+            }
+            // overlaps that only show once interfaces and CIDRs are resolved to addresses
+            let resolve = |selectors: &[String]| crate::bind_addr::resolve(selectors, &host, false);
+            let known = config.listener_conflicts(&plain_addresses);
+            for conflict in config.listener_conflicts(&resolve) {
+                if !known.iter().any(|k| k.path == conflict.path) {
+                    problems.push(conflict);
+                }
+            }
+        }
+        Err(e) => problems.push(Problem::new(
+            "$.bind_addrs".to_string(),
+            format!("cannot list host addresses: {e}"),
+        )),
+    }
+    if config
+        .mappings
+        .iter()
+        .any(|m| m.is_transparent(config) || m.mode().intercepts())
+    {
+        problems.extend(capability_problems(config));
+    }
+    problems
+}
+
+fn selector_problem(selector: &BindSelector, host: &[HostAddress]) -> Option<String> {
+    let found = match selector {
+        BindSelector::Addr(addr) => host.iter().any(|h| h.addr == *addr),
+        BindSelector::Net(net) => host.iter().any(|h| net.contains(&h.addr)),
+        BindSelector::Interface(name) => host.iter().any(|h| &h.interface == name),
+    };
+    if found {
+        return None;
+    }
+    Some(match selector {
+        BindSelector::Addr(addr) => {
+            format!("{addr} is not an address of any interface on this host")
+        }
+        BindSelector::Net(net) => format!("no address of this host is in {net}"),
+        BindSelector::Interface(name) => {
+            format!("no interface named {name} is up with an IPv4 address")
+        }
+    })
+}
+
+/// The paths of the settings turning on `setting` for the mappings `uses` holds for: the field of
+/// a mapping that `decided_by` returns if any, otherwise the global setting.
+fn setting_paths(
+    config: &Config,
+    setting: &str,
+    decided_by: impl Fn(&Mapping) -> Option<&'static str>,
+    uses: impl Fn(&Mapping) -> bool,
+) -> Vec<String> {
+    let mut paths = vec![];
+    for (i, mapping) in config.mappings.iter().enumerate() {
+        if !uses(mapping) {
+            continue;
+        }
+        let path = match decided_by(mapping) {
+            Some(field) => format!("$.mappings[{i}].{field}"),
+            None => format!("$.{setting}"),
+        };
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+fn transparent_paths(config: &Config) -> Vec<String> {
+    setting_paths(
+        config,
+        "transparent",
+        |m| match m.mode() {
+            MappingMode::Tproxy => Some("mode"),
+            _ => m.transparent.map(|_| "transparent"),
+        },
+        |m| m.is_transparent(config),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn capability_problems(config: &Config) -> Vec<Problem> {
+    let mut problems = vec![];
+    let caps = match effective_capabilities() {
+        Ok(caps) => caps,
+        Err(e) => {
+            return vec![Problem::new(
+                "$.transparent".to_string(),
+                format!("cannot determine process capabilities: {e}"),
+            )]
+        }
+    };
+    let has_cap = |cap: u32| caps & (1 << cap) != 0;
+    if !has_cap(CAP_NET_ADMIN) && !has_cap(CAP_NET_RAW) {
+        for path in transparent_paths(config) {
+            problems.push(Problem::new(
+                path,
+                "transparent mode needs CAP_NET_ADMIN or CAP_NET_RAW".to_string(),
+            ));
+        }
+    }
+    if !has_cap(CAP_NET_ADMIN) {
+        let managed = setting_paths(
+            config,
+            "manage_iptables",
+            |m| m.manage_iptables.map(|_| "manage_iptables"),
+            |m| m.is_managed(config),
+        );
+        for path in managed {
+            problems.push(Problem::new(
+                path,
+                "managing iptables and policy routing needs CAP_NET_ADMIN".to_string(),
+            ));
+        }
+        for (i, mapping) in config.mappings.iter().enumerate() {
+            if mapping.mode().intercepts() {
+                problems.push(Problem::new(
+                    format!("$.mappings[{i}].mode"),
+                    format!(
+                        "{} mode sets SO_MARK on upstream sockets, which needs CAP_NET_ADMIN",
+                        mapping.mode().as_str()
+                    ),
+                ));
+            }
+        }
+    }
+    if config.return_routing == ReturnRouting::SoMark && !has_cap(CAP_NET_ADMIN) {
+        problems.push(Problem::new(
+            "$.return_routing".to_string(),
+            "setting SO_MARK on upstream sockets needs CAP_NET_ADMIN".to_string(),
+        ));
+    }
+    problems
+}
+
+#[cfg(target_os = "linux")]
+fn effective_capabilities() -> anyhow::Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let cap_eff = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .ok_or_else(|| anyhow::anyhow!("CapEff missing from /proc/self/status"))?;
+    Ok(u64::from_str_radix(cap_eff.trim(), 16)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn capability_problems(config: &Config) -> Vec<Problem> {
+    transparent_paths(config)
+        .into_iter()
+        .map(|path| {
+            Problem::new(
+                path,
+                "transparent mode is only supported on Linux".to_string(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_every_problem() {
+        let path = std::env::temp_dir().join(format!("stargate-check-{}.json", std::process::id()));
+        tokio::fs::write(
+            &path,
+            r#"{
+                "transparent": false,
+                "manage_iptables": false,
+                "should_exit": false,
+                "bind_addrs": ["127.0.0.1", "not-an-ip", "192.0.2.123"],
+                "mappings": [
+                    {"local_port": 2001, "target_address": "10.0.0.1", "hairpin_net": "10.0.0.0/33"},
+                    {"local_port": 2001, "target_address": "10.0.0.1:80"}
+                ]
+            }"#,
+        )
+        .await
+        .unwrap();
+        let problems = check_file(&path, ConfigFormat::Json).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "$.mappings[0].target_address",
+                "$.mappings[0].hairpin_net",
+                "$.mappings[1].local_port",
+                "$.bind_addrs[1]",
+                "$.bind_addrs[2]",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_json_path_of_type_errors() {
+        let path =
+            std::env::temp_dir().join(format!("stargate-check-type-{}.json", std::process::id()));
+        tokio::fs::write(
+            &path,
+            r#"{"transparent": false, "manage_iptables": false, "should_exit": false,
+                "mappings": [{"local_port": "2001", "target_address": "10.0.0.1:80"}]}"#,
+        )
+        .await
+        .unwrap();
+        let problems = check_file(&path, ConfigFormat::Json).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "$.mappings[0].local_port");
+    }
+
+    #[test]
+    fn transparent_reported_where_it_is_turned_on() {
+        let config: Config = ConfigFormat::Json
+            .parse(
+                br#"{"transparent": false, "manage_iptables": true, "should_exit": false,
+                    "mappings": [
+                        {"local_port": 2001, "target_address": "10.0.0.1:80"},
+                        {"local_port": 2002, "target_address": "10.0.0.2:80", "transparent": true},
+                        {"local_port": 2003, "mode": "tproxy",
+                         "destinations": {"nets": ["10.1.0.0/16"]}},
+                        {"local_port": 2004, "mode": "redirect",
+                         "destinations": {"nets": ["10.2.0.0/16"]}}
+                    ]}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            transparent_paths(&config),
+            vec!["$.mappings[1].transparent", "$.mappings[2].mode"]
+        );
+
+        let config = Config {
+            transparent: true,
+            ..config
+        };
+        assert_eq!(
+            transparent_paths(&config),
+            vec![
+                "$.transparent",
+                "$.mappings[1].transparent",
+                "$.mappings[2].mode"
+            ]
+        );
+    }
+}
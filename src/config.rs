@@ -1,19 +1,68 @@
+use crate::bind_addr::{self, BindSelector};
 use async_trait::async_trait;
 use ipnet::Ipv4Net;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddrV4};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-#[derive(Serialize, Deserialize, PartialEq, Copy, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct IntMapping {
+    pub name: String,
     pub local_bind: SocketAddrV4,
-    pub target_address: SocketAddrV4,
+    /// Unset for intercepting mappings, which connect to wherever the client was connecting.
+    pub target_address: Option<SocketAddrV4>,
+    pub mode: MappingMode,
+    pub destinations: Option<DestinationFilter>,
     pub transparent: bool,
     pub manage_iptables: bool,
     pub hairpin_net: Option<Ipv4Net>,
+    pub idle_timeout_secs: Option<u64>,
+    pub half_close_timeout_secs: u64,
+    pub drain_timeout_secs: u64,
+    pub bind_retry_initial_secs: u64,
+    pub bind_retry_max_secs: u64,
+    pub socket_options: SocketOptions,
+    /// Mark set on upstream sockets when replies are routed back by connection mark rather than
+    /// by per-target rules.
+    pub upstream_mark: Option<u32>,
 }
 
 impl IntMapping {
+    /// Whether return routing or interception for this mapping's traffic is set up by us.
+    pub fn is_managed(&self) -> bool {
+        (self.transparent || self.mode.intercepts()) && self.manage_iptables
+    }
+
+    /// The target whose replies need a mangle rule, if this mapping uses one.
+    pub fn return_rule_target(&self) -> Option<SocketAddrV4> {
+        self.target_address
+            .filter(|_| self.is_managed() && self.upstream_mark.is_none())
+    }
+
+    /// The target as shown in logs, metrics and the admin API.
+    pub fn target_label(&self) -> String {
+        match self.target_address {
+            Some(target) => target.to_string(),
+            None => self.mode.as_str().to_string(),
+        }
+    }
+
+    /// The traffic diverted to this mapping's listener, for intercepting mappings.
+    pub fn intercept(&self) -> Option<Intercept> {
+        if !self.mode.intercepts() {
+            return None;
+        }
+        Some(Intercept {
+            mode: self.mode,
+            destinations: self.destinations.clone()?,
+            listener: self.local_bind,
+            upstream_mark: self.upstream_mark,
+        })
+    }
+
     pub(crate) fn connection_is_hairpin(&self, p0: &Ipv4Addr) -> bool {
         match self.hairpin_net {
             None => false,
@@ -22,46 +71,711 @@ impl IntMapping {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Mapping {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
     pub local_port: u16,
-    pub target_address: String,
+    /// Where connections go on to. Required for `MappingMode::Proxy` and unused otherwise.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub target_address: Option<String>,
     pub hairpin_net: Option<String>,
+    /// Overrides `Config::bind_addrs` for this mapping.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bind_addrs: Option<Vec<String>>,
+    /// Overrides `Config::transparent` for this mapping.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub transparent: Option<bool>,
+    /// Overrides `Config::manage_iptables` for this mapping.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub manage_iptables: Option<bool>,
+    /// Overrides individual `Config::socket_options` for this mapping.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub socket_options: Option<SocketOptions>,
+    /// `MappingMode::Proxy` if unset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mode: Option<MappingMode>,
+    /// The traffic an intercepting mapping takes over.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub destinations: Option<Destinations>,
+}
+
+/// How connections reach a mapping's listener, and so where they go on to.
+#[derive(
+    Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Debug, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingMode {
+    /// Clients connect to the listener, and connections go on to `target_address`.
+    #[default]
+    Proxy,
+    /// Connections to `destinations` are diverted to the listener by a TPROXY rule, and go on to
+    /// the destination the client was connecting to. Always transparent.
+    Tproxy,
+    /// Connections to `destinations`, from other hosts or this one, are diverted to the
+    /// listener's port by REDIRECT rules, and go on to the destination the client was connecting
+    /// to as given by SO_ORIGINAL_DST. The rules redirect to the address a connection arrives on,
+    /// or to 127.0.0.1 for this host's own connections, so the listener is always bound on
+    /// 0.0.0.0.
+    Redirect,
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+impl MappingMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MappingMode::Proxy => "proxy",
+            MappingMode::Tproxy => "tproxy",
+            MappingMode::Redirect => "redirect",
+        }
+    }
+
+    /// Whether the listener gets connections meant for other destinations.
+    pub fn intercepts(self) -> bool {
+        self != MappingMode::Proxy
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Destinations {
+    /// CIDRs
+    pub nets: Vec<String>,
+    /// Any port if empty.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub ports: Vec<u16>,
+}
+
+impl Destinations {
+    fn to_filter(&self) -> anyhow::Result<DestinationFilter> {
+        Ok(DestinationFilter {
+            nets: self
+                .nets
+                .iter()
+                .map(|net| net.parse::<Ipv4Net>())
+                .collect::<Result<_, _>>()?,
+            ports: self.ports.clone(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Debug)]
+pub struct DestinationFilter {
+    pub nets: Vec<Ipv4Net>,
+    /// Any port if empty.
+    pub ports: Vec<u16>,
+}
+
+impl DestinationFilter {
+    pub fn matches(&self, destination: &SocketAddrV4) -> bool {
+        self.nets.iter().any(|net| net.contains(destination.ip()))
+            && (self.ports.is_empty() || self.ports.contains(&destination.port()))
+    }
+}
+
+/// Traffic to `destinations` that firewall rules divert to `listener`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Debug)]
+pub struct Intercept {
+    /// Which rules divert the traffic.
+    pub mode: MappingMode,
+    pub destinations: DestinationFilter,
+    pub listener: SocketAddrV4,
+    /// Connections carrying this mark are our own upstream connections, and are left alone.
+    pub upstream_mark: Option<u32>,
+}
+
+/// Options for a mapping's listener, the client sockets it accepts and the upstream sockets it
+/// connects. Anything unset keeps the system default.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    /// Listen backlog, 128 if unset.
+    pub backlog: Option<u32>,
+    /// SO_REUSEPORT on the listener, on if unset. Without it, a proxy whose mapping changed
+    /// stops accepting before its replacement binds, so connections can be refused in between.
+    pub reuseport: Option<bool>,
+    pub nodelay: Option<bool>,
+    /// SO_KEEPALIVE, also turned on by setting any of the keepalive parameters.
+    pub keepalive: Option<bool>,
+    pub keepalive_idle_secs: Option<u64>,
+    pub keepalive_interval_secs: Option<u64>,
+    pub keepalive_count: Option<u32>,
+    /// TCP_USER_TIMEOUT
+    pub user_timeout_ms: Option<u64>,
+    /// SO_RCVBUF
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF
+    pub send_buffer_size: Option<usize>,
+    /// TCP_DEFER_ACCEPT on the listener.
+    pub defer_accept_secs: Option<u32>,
+    /// TCP_FASTOPEN queue length for the listener. Setting it also turns on
+    /// TCP_FASTOPEN_CONNECT for upstream connections.
+    pub fastopen: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn reuses_port(&self) -> bool {
+        self.reuseport.unwrap_or(true)
+    }
+
+    /// These options, with anything unset taken from `defaults`.
+    pub fn or(&self, defaults: &SocketOptions) -> SocketOptions {
+        SocketOptions {
+            backlog: self.backlog.or(defaults.backlog),
+            reuseport: self.reuseport.or(defaults.reuseport),
+            nodelay: self.nodelay.or(defaults.nodelay),
+            keepalive: self.keepalive.or(defaults.keepalive),
+            keepalive_idle_secs: self.keepalive_idle_secs.or(defaults.keepalive_idle_secs),
+            keepalive_interval_secs: self
+                .keepalive_interval_secs
+                .or(defaults.keepalive_interval_secs),
+            keepalive_count: self.keepalive_count.or(defaults.keepalive_count),
+            user_timeout_ms: self.user_timeout_ms.or(defaults.user_timeout_ms),
+            recv_buffer_size: self.recv_buffer_size.or(defaults.recv_buffer_size),
+            send_buffer_size: self.send_buffer_size.or(defaults.send_buffer_size),
+            defer_accept_secs: self.defer_accept_secs.or(defaults.defer_accept_secs),
+            fastopen: self.fastopen.or(defaults.fastopen),
+        }
+    }
+
+    pub fn keepalive_enabled(&self) -> bool {
+        self.keepalive.unwrap_or(
+            self.keepalive_idle_secs.is_some()
+                || self.keepalive_interval_secs.is_some()
+                || self.keepalive_count.is_some(),
+        )
+    }
+}
+
+impl Mapping {
+    pub fn mode(&self) -> MappingMode {
+        self.mode.unwrap_or_default()
+    }
+
+    /// What identifies this mapping in the control API: its name, or else its own bind address
+    /// selectors and local port, e.g. `eth0,10.0.0.1:8080`, or just the port if it has none.
+    pub fn id(&self) -> String {
+        match (&self.name, &self.bind_addrs) {
+            (Some(name), _) => name.clone(),
+            (None, Some(bind_addrs)) if !bind_addrs.is_empty() => {
+                format!("{}:{}", bind_addrs.join(","), self.local_port)
+            }
+            (None, _) => self.local_port.to_string(),
+        }
+    }
+
+    /// Where this mapping listens, with `bind_addrs` the config-wide bind address selectors and
+    /// `addresses` giving the addresses a list of selectors stands for.
+    pub fn listeners(
+        &self,
+        bind_addrs: &[String],
+        addresses: &impl Fn(&[String]) -> Vec<Ipv4Addr>,
+    ) -> Vec<SocketAddrV4> {
+        let bound = match self.mode() {
+            MappingMode::Redirect => vec![Ipv4Addr::UNSPECIFIED],
+            _ => addresses(self.bind_addrs.as_deref().unwrap_or(bind_addrs)),
+        };
+        bound
+            .into_iter()
+            .map(|addr| SocketAddrV4::new(addr, self.local_port))
+            .collect()
+    }
+
+    pub fn is_transparent(&self, config: &Config) -> bool {
+        self.mode() == MappingMode::Tproxy || self.transparent.unwrap_or(config.transparent)
+    }
+
+    pub fn is_managed(&self, config: &Config) -> bool {
+        (self.is_transparent(config) || self.mode().intercepts())
+            && self.manage_iptables.unwrap_or(config.manage_iptables)
+    }
+
+    /// Problems with the target or destinations of the mapping at `$.mappings[i]` for its mode.
+    fn mode_problems(&self, i: usize) -> Vec<Problem> {
+        let mut problems = vec![];
+        let path = |field: &str| format!("$.mappings[{i}].{field}");
+        match (&self.target_address, self.mode()) {
+            (None, MappingMode::Proxy) => problems.push(Problem::new(
+                path("target_address"),
+                "target_address is required".to_string(),
+            )),
+            (Some(target), MappingMode::Proxy) => {
+                if let Err(e) = target.parse::<SocketAddrV4>() {
+                    problems.push(Problem::new(
+                        path("target_address"),
+                        format!("invalid address '{target}': {e}"),
+                    ));
+                }
+            }
+            (Some(_), mode) => problems.push(Problem::new(
+                path("target_address"),
+                format!(
+                    "{} mappings connect to the original destination",
+                    mode.as_str()
+                ),
+            )),
+            (None, _) => (),
+        }
+        match &self.destinations {
+            None if self.mode().intercepts() => problems.push(Problem::new(
+                path("destinations"),
+                format!(
+                    "destinations are required for {} mappings",
+                    self.mode().as_str()
+                ),
+            )),
+            None => (),
+            Some(_) if !self.mode().intercepts() => problems.push(Problem::new(
+                path("destinations"),
+                "destinations are only used by intercepting mappings".to_string(),
+            )),
+            Some(destinations) => {
+                if destinations.nets.is_empty() {
+                    problems.push(Problem::new(
+                        path("destinations.nets"),
+                        "at least one CIDR is required".to_string(),
+                    ));
+                }
+                for (j, net) in destinations.nets.iter().enumerate() {
+                    if let Err(e) = net.parse::<Ipv4Net>() {
+                        problems.push(Problem::new(
+                            path(&format!("destinations.nets[{j}]")),
+                            format!("invalid CIDR '{net}': {e}"),
+                        ));
+                    }
+                }
+                if destinations.ports.contains(&0) {
+                    problems.push(Problem::new(
+                        path("destinations.ports"),
+                        "ports must not be 0".to_string(),
+                    ));
+                }
+            }
+        }
+        if self.mode() == MappingMode::Redirect && self.bind_addrs.is_some() {
+            problems.push(Problem::new(
+                path("bind_addrs"),
+                "redirect mappings always listen on 0.0.0.0".to_string(),
+            ));
+        }
+        if self.mode() == MappingMode::Tproxy && self.transparent == Some(false) {
+            problems.push(Problem::new(
+                path("transparent"),
+                format!("{} mappings are always transparent", self.mode().as_str()),
+            ));
+        }
+        problems
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
 pub struct Config {
     pub mappings: Vec<Mapping>,
     pub transparent: bool,
     pub manage_iptables: bool,
     pub should_exit: bool,
+    /// Addresses to listen on: IPv4 addresses, CIDRs or interface names. Empty means every
+    /// address of the host.
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub bind_addrs: Vec<String>,
+    /// Close connections that have carried no data in either direction for this long.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Once one side of a connection has closed its end, close the connection when the other
+    /// side has sent nothing for this long, 2 if unset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub half_close_timeout_secs: Option<u64>,
+    /// When a proxy is stopped or replaced, close its remaining connections after this long, 30
+    /// if unset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub drain_timeout_secs: Option<u64>,
+    /// Delay before retrying a listener that failed to bind, doubling after each further failure.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bind_retry_initial_secs: Option<u64>,
+    /// Longest delay between bind retries.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bind_retry_max_secs: Option<u64>,
+    /// Reject a config, or fail to start, if any of its new listeners can't be bound, instead of
+    /// retrying them in the background.
+    #[serde(default)]
+    pub strict_bind: bool,
+    #[serde(default)]
+    pub socket_options: SocketOptions,
+    /// How replies from targets of transparent mappings are steered back to Stargate.
+    #[serde(default)]
+    pub return_routing: ReturnRouting,
+    /// The mark used for `ReturnRouting::SoMark`, 2 if unset.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub upstream_mark: Option<u32>,
+    /// How return rules are installed. Only read at startup.
+    #[serde(default)]
+    pub firewall_backend: FirewallBackendKind,
+    /// Only read at startup.
+    #[serde(default)]
+    pub policy_routing: PolicyRouting,
+    /// How often to check the policy routing and firewall rules are still in place, and put back
+    /// any that have gone missing, 30 if unset. 0 disables the checks.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reconcile_interval_secs: Option<u64>,
+}
+
+/// The policy routing rule that sends marked replies from targets to a table delivering them
+/// locally.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyRouting {
+    pub table: u32,
+    pub fwmark: u32,
+    pub fwmark_mask: u32,
+    /// Priority of the rule; the kernel picks one if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+}
+
+impl Default for PolicyRouting {
+    fn default() -> Self {
+        PolicyRouting {
+            table: crate::iptables_setup::ROUTING_TABLE,
+            fwmark: crate::iptables_setup::FWMARK,
+            fwmark_mask: crate::iptables_setup::FWMARK_MASK,
+            priority: None,
+        }
+    }
+}
+
+impl PolicyRouting {
+    /// Whether a packet marked with `mark` gets routed by this rule.
+    pub fn matches(&self, mark: u32) -> bool {
+        mark & self.fwmark_mask == self.fwmark
+    }
+
+    fn problems(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        // unspec, default, main and local
+        if [0, 253, 254, 255].contains(&self.table) {
+            problems.push(Problem::new(
+                "$.policy_routing.table".to_string(),
+                format!("table {} is reserved", self.table),
+            ));
+        }
+        if self.fwmark == 0 || self.fwmark & !self.fwmark_mask != 0 {
+            problems.push(Problem::new(
+                "$.policy_routing.fwmark".to_string(),
+                format!(
+                    "fwmark {:#x} must be non-zero and within fwmark_mask {:#x}",
+                    self.fwmark, self.fwmark_mask
+                ),
+            ));
+        }
+        problems
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FirewallBackendKind {
+    /// nftables if the `nft` binary is present and `iptables` isn't the legacy one, otherwise
+    /// iptables.
+    #[default]
+    Auto,
+    Iptables,
+    Nftables,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReturnRouting {
+    /// A mangle rule per target, marking packets from the target's address and port.
+    #[default]
+    Mangle,
+    /// Upstream sockets get SO_MARK, which is saved to the connection and restored onto its
+    /// replies by two static rules.
+    SoMark,
 }
 
+pub const DEFAULT_UPSTREAM_MARK: u32 = 2;
+
+pub const DEFAULT_HALF_CLOSE_TIMEOUT_SECS: u64 = 2;
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_BIND_RETRY_INITIAL_SECS: u64 = 1;
+pub const DEFAULT_BIND_RETRY_MAX_SECS: u64 = 60;
+pub const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 30;
+
 impl Config {
+    /// Expects bind addresses to have been resolved to plain addresses, see `resolve`.
     pub fn to_int_mappings(&self) -> Result<Vec<IntMapping>, anyhow::Error> {
         let mut result = vec![];
+        let wildcard = vec![Ipv4Addr::UNSPECIFIED.to_string()];
         for mapping in &self.mappings {
             let hairpin_net = match &mapping.hairpin_net {
                 Some(hairpin_net) => Some(hairpin_net.parse::<Ipv4Net>()?),
                 None => None,
             };
-            for bind_addr in &self.bind_addrs {
+            let target_address = match (&mapping.target_address, mapping.mode()) {
+                (Some(target), MappingMode::Proxy) => Some(target.parse::<SocketAddrV4>()?),
+                (None, MappingMode::Proxy) => anyhow::bail!(
+                    "mapping on port {} has no target_address",
+                    mapping.local_port
+                ),
+                _ => None,
+            };
+            let destinations = match &mapping.destinations {
+                Some(destinations) => Some(destinations.to_filter()?),
+                None if mapping.mode().intercepts() => anyhow::bail!(
+                    "intercepting mapping on port {} has no destinations",
+                    mapping.local_port
+                ),
+                None => None,
+            };
+            let bind_addrs = match mapping.mode() {
+                MappingMode::Redirect => &wildcard,
+                _ => mapping.bind_addrs.as_ref().unwrap_or(&self.bind_addrs),
+            };
+            for bind_addr in bind_addrs {
                 result.push(IntMapping {
+                    name: mapping
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| mapping.local_port.to_string()),
                     local_bind: SocketAddrV4::new(
                         bind_addr.parse::<Ipv4Addr>()?,
                         mapping.local_port,
                     ),
-                    target_address: mapping.target_address.parse::<SocketAddrV4>()?,
-                    transparent: self.transparent,
-                    manage_iptables: self.manage_iptables,
+                    target_address,
+                    mode: mapping.mode(),
+                    destinations: destinations.clone(),
+                    transparent: mapping.is_transparent(self),
+                    manage_iptables: mapping.manage_iptables.unwrap_or(self.manage_iptables),
                     hairpin_net,
+                    idle_timeout_secs: self.idle_timeout_secs,
+                    half_close_timeout_secs: self
+                        .half_close_timeout_secs
+                        .unwrap_or(DEFAULT_HALF_CLOSE_TIMEOUT_SECS),
+                    drain_timeout_secs: self
+                        .drain_timeout_secs
+                        .unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS),
+                    bind_retry_initial_secs: self
+                        .bind_retry_initial_secs
+                        .unwrap_or(DEFAULT_BIND_RETRY_INITIAL_SECS),
+                    bind_retry_max_secs: self
+                        .bind_retry_max_secs
+                        .unwrap_or(DEFAULT_BIND_RETRY_MAX_SECS),
+                    socket_options: match &mapping.socket_options {
+                        Some(options) => options.or(&self.socket_options),
+                        None => self.socket_options.clone(),
+                    },
+                    // replies from arbitrary destinations can't be matched by return rules, and
+                    // the mark keeps upstream connections from being intercepted again
+                    upstream_mark: (mapping.mode().intercepts()
+                        || (self.return_routing == ReturnRouting::SoMark
+                            && mapping.is_transparent(self)))
+                    .then(|| self.upstream_mark.unwrap_or(DEFAULT_UPSTREAM_MARK)),
                 });
             }
         }
         Ok(result)
     }
+
+    /// Every problem with this config that can be found without looking at the host, each
+    /// tagged with the JSON path of the offending value.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        // only plain addresses are known before resolving, see `listener_conflicts`
+        let mut listeners = vec![];
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            problems.extend(mapping.mode_problems(i));
+            if let Some(hairpin_net) = &mapping.hairpin_net {
+                if let Err(e) = hairpin_net.parse::<Ipv4Net>() {
+                    problems.push(Problem::new(
+                        format!("$.mappings[{i}].hairpin_net"),
+                        format!("invalid CIDR '{hairpin_net}': {e}"),
+                    ));
+                }
+            }
+            for (j, bind_addr) in mapping.bind_addrs.iter().flatten().enumerate() {
+                if let Err(e) = bind_addr.parse::<BindSelector>() {
+                    problems.push(Problem::new(
+                        format!("$.mappings[{i}].bind_addrs[{j}]"),
+                        e.to_string(),
+                    ));
+                }
+            }
+            if mapping.local_port == 0 {
+                problems.push(Problem::new(
+                    format!("$.mappings[{i}].local_port"),
+                    "local_port must not be 0".to_string(),
+                ));
+            } else if let Some(conflict) =
+                self.listener_conflict(i, &plain_addresses, &mut listeners)
+            {
+                problems.push(conflict);
+            }
+        }
+        for (i, bind_addr) in self.bind_addrs.iter().enumerate() {
+            if let Err(e) = bind_addr.parse::<BindSelector>() {
+                problems.push(Problem::new(format!("$.bind_addrs[{i}]"), e.to_string()));
+            }
+        }
+        problems.extend(self.policy_routing.problems());
+        let intercepting = self.mappings.iter().any(|m| m.mode().intercepts());
+        let upstream_mark = if intercepting || self.return_routing == ReturnRouting::SoMark {
+            Some(self.upstream_mark.unwrap_or(DEFAULT_UPSTREAM_MARK))
+        } else {
+            self.upstream_mark
+        };
+        if let Some(mark) = upstream_mark {
+            if mark == 0 || self.policy_routing.matches(mark) {
+                problems.push(Problem::new(
+                    "$.upstream_mark".to_string(),
+                    format!(
+                        "upstream_mark must not be 0 or match the return routing mark {:#x}/{:#x}",
+                        self.policy_routing.fwmark, self.policy_routing.fwmark_mask
+                    ),
+                ));
+            }
+        }
+        for (setting, secs) in [
+            ("idle_timeout_secs", self.idle_timeout_secs),
+            ("half_close_timeout_secs", self.half_close_timeout_secs),
+        ] {
+            if secs == Some(0) {
+                problems.push(Problem::new(
+                    format!("$.{setting}"),
+                    format!("{setting} must not be 0"),
+                ));
+            }
+        }
+        let initial = self
+            .bind_retry_initial_secs
+            .unwrap_or(DEFAULT_BIND_RETRY_INITIAL_SECS);
+        let max = self
+            .bind_retry_max_secs
+            .unwrap_or(DEFAULT_BIND_RETRY_MAX_SECS);
+        if initial == 0 {
+            problems.push(Problem::new(
+                "$.bind_retry_initial_secs".to_string(),
+                "bind_retry_initial_secs must not be 0".to_string(),
+            ));
+        } else if initial > max {
+            problems.push(Problem::new(
+                "$.bind_retry_max_secs".to_string(),
+                format!("bind_retry_max_secs {max} is less than bind_retry_initial_secs {initial}"),
+            ));
+        }
+        problems
+    }
+
+    /// Problems with mappings listening on an address and port that an earlier mapping listens
+    /// on too, with `addresses` giving the addresses a list of bind address selectors stands for.
+    pub fn listener_conflicts(
+        &self,
+        addresses: &impl Fn(&[String]) -> Vec<Ipv4Addr>,
+    ) -> Vec<Problem> {
+        let mut listeners = vec![];
+        (0..self.mappings.len())
+            .filter_map(|i| self.listener_conflict(i, addresses, &mut listeners))
+            .collect()
+    }
+
+    /// Adds where mapping `i` listens to `listeners`, returning a problem if one of the earlier
+    /// mappings there listens on the same port of the same address or of 0.0.0.0.
+    fn listener_conflict(
+        &self,
+        i: usize,
+        addresses: &impl Fn(&[String]) -> Vec<Ipv4Addr>,
+        listeners: &mut Vec<(SocketAddrV4, usize)>,
+    ) -> Option<Problem> {
+        let mapping = &self.mappings[i];
+        let mine = mapping.listeners(&self.bind_addrs, addresses);
+        let conflict = mine.iter().find_map(|listener| {
+            listeners
+                .iter()
+                .find(|(other, _)| overlaps(*other, *listener))
+        });
+        let problem = conflict.map(|(other, first)| {
+            Problem::new(
+                format!("$.mappings[{i}].local_port"),
+                format!(
+                    "local_port {} overlaps {other} of $.mappings[{first}]",
+                    mapping.local_port
+                ),
+            )
+        });
+        listeners.extend(mine.into_iter().map(|listener| (listener, i)));
+        problem
+    }
+
+    /// Fails with every problem found by `problems`, if there are any.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(problems
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")))
+        }
+    }
+
+    /// Resolves bind address selectors, globally and per mapping, to the host addresses they
+    /// pick, and produces the internal mappings for this config.
+    pub async fn resolve(
+        mut self,
+        bind_loopback: bool,
+    ) -> anyhow::Result<(Config, Vec<IntMapping>)> {
+        let host = bind_addr::host_addresses()?;
+        let addresses = |selectors: &[String]| bind_addr::resolve(selectors, &host, bind_loopback);
+        if let Some(conflict) = self.listener_conflicts(&addresses).first() {
+            anyhow::bail!("{conflict}");
+        }
+        let resolve = |selectors: &[String]| -> anyhow::Result<Vec<String>> {
+            let selectors = selectors
+                .iter()
+                .map(|s| s.parse())
+                .collect::<anyhow::Result<Vec<BindSelector>>>()?;
+            Ok(bind_addr::select(&selectors, &host, bind_loopback)
+                .iter()
+                .map(|a| a.to_string())
+                .collect())
+        };
+        self.bind_addrs = resolve(&self.bind_addrs)?;
+        for mapping in &mut self.mappings {
+            if let Some(bind_addrs) = &mapping.bind_addrs {
+                mapping.bind_addrs = Some(resolve(bind_addrs)?);
+            }
+        }
+
+        let int_mappings = self.to_int_mappings()?;
+        Ok((self, int_mappings))
+    }
+}
+
+/// The addresses bind address selectors are known to stand for without looking at the host:
+/// the plain addresses among them, or 0.0.0.0 if there are none, as that means all of them.
+pub fn plain_addresses(selectors: &[String]) -> Vec<Ipv4Addr> {
+    if selectors.is_empty() {
+        return vec![Ipv4Addr::UNSPECIFIED];
+    }
+    selectors.iter().filter_map(|s| s.parse().ok()).collect()
+}
+
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn new(path: String, message: String) -> Problem {
+        Problem { path, message }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
 }
 
 #[async_trait]
@@ -69,26 +783,603 @@ pub trait ConfigProvider {
     async fn read_config(&self) -> anyhow::Result<(Config, Vec<IntMapping>)>;
 }
 
+#[derive(clap::ValueEnum, Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, falling back to JSON.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        ConfigFormat::from_extension(path).unwrap_or(ConfigFormat::Json)
+    }
+
+    /// The format of a file with a recognised config extension.
+    pub fn from_extension(path: &Path) -> Option<ConfigFormat> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Some(ConfigFormat::Json),
+            Some("toml") => Some(ConfigFormat::Toml),
+            Some("yaml") | Some("yml") => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Deserializes `bytes` in this format. Parse errors include the line and column.
+    pub fn parse<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_slice(bytes)?,
+            ConfigFormat::Toml => toml::from_str(std::str::from_utf8(bytes)?)?,
+            ConfigFormat::Yaml => serde_yaml::from_slice(bytes)?,
+        })
+    }
+}
+
+/// Name (without extension) of the base file in a config directory.
+pub const BASE_FILE_STEM: &str = "stargate";
+
+/// The mappings one fragment file in a config directory contributes.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Fragment {
+    pub mappings: Vec<Mapping>,
+}
+
+/// The files making up a config directory: the base file with the global settings, and the
+/// fragment files contributing mappings, in name order.
+pub struct ConfigDir {
+    pub base: PathBuf,
+    pub fragments: Vec<PathBuf>,
+}
+
+impl ConfigDir {
+    pub async fn list(dir: &Path) -> anyhow::Result<ConfigDir> {
+        let mut base = vec![];
+        let mut fragments = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !entry.file_type().await?.is_file() || ConfigFormat::from_extension(&path).is_none()
+            {
+                continue;
+            }
+            if path.file_stem().and_then(|s| s.to_str()) == Some(BASE_FILE_STEM) {
+                base.push(path);
+            } else {
+                fragments.push(path);
+            }
+        }
+        fragments.sort();
+        match <[PathBuf; 1]>::try_from(base) {
+            Ok([base]) => Ok(ConfigDir { base, fragments }),
+            Err(base) if base.is_empty() => Err(anyhow::anyhow!(
+                "no {BASE_FILE_STEM}.{{json,toml,yaml}} base file in {}",
+                dir.display()
+            )),
+            Err(_) => Err(anyhow::anyhow!(
+                "more than one {BASE_FILE_STEM} base file in {}",
+                dir.display()
+            )),
+        }
+    }
+}
+
+/// Whether listeners on `a` and `b` would take the same connections.
+fn overlaps(a: SocketAddrV4, b: SocketAddrV4) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+/// The listeners of `mappings`, each with `owner`, for `listener_taken`. `bind_addrs` are the
+/// config-wide bind address selectors and `addresses` gives the addresses selectors stand for.
+pub fn owned_listeners(
+    mappings: &[Mapping],
+    owner: &str,
+    bind_addrs: &[String],
+    addresses: &impl Fn(&[String]) -> Vec<Ipv4Addr>,
+) -> Vec<(SocketAddrV4, String)> {
+    mappings
+        .iter()
+        .flat_map(|mapping| mapping.listeners(bind_addrs, addresses))
+        .map(|listener| (listener, owner.to_string()))
+        .collect()
+}
+
+/// Describes the first mapping in `mappings` listening where one of `taken` already does.
+pub fn listener_taken(
+    taken: &[(SocketAddrV4, String)],
+    mappings: &[Mapping],
+    bind_addrs: &[String],
+    addresses: &impl Fn(&[String]) -> Vec<Ipv4Addr>,
+) -> Option<String> {
+    mappings.iter().find_map(|mapping| {
+        mapping
+            .listeners(bind_addrs, addresses)
+            .into_iter()
+            .find_map(|listener| taken.iter().find(|(other, _)| overlaps(*other, listener)))
+            .map(|(other, owner)| {
+                format!(
+                    "local_port {} overlaps {other} of {owner}",
+                    mapping.local_port
+                )
+            })
+    })
+}
+
+/// Reads the config from a single file, or from a directory holding a base file and any number
+/// of fragment files.
 pub struct FileConfigProvider {
-    pub config_path: PathBuf,
-    pub bind_loopback: bool,
+    config_path: PathBuf,
+    /// Overrides detecting the format from the file extension. Files in a config directory
+    /// always use their extension.
+    config_format: Option<ConfigFormat>,
+    bind_loopback: bool,
+    /// The last version of each fragment that was accepted, used while a fragment is invalid.
+    fragments: Mutex<HashMap<PathBuf, Vec<Mapping>>>,
+}
+
+impl FileConfigProvider {
+    pub fn new(
+        config_path: PathBuf,
+        config_format: Option<ConfigFormat>,
+        bind_loopback: bool,
+    ) -> FileConfigProvider {
+        FileConfigProvider {
+            config_path,
+            config_format,
+            bind_loopback,
+            fragments: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn format(&self) -> ConfigFormat {
+        self.config_format
+            .unwrap_or_else(|| ConfigFormat::from_path(&self.config_path))
+    }
+
+    async fn read_dir(&self) -> anyhow::Result<Config> {
+        let dir = ConfigDir::list(&self.config_path).await?;
+        let mut config: Config = read_file(&dir.base, ConfigFormat::from_path(&dir.base)).await?;
+        config
+            .validate()
+            .map_err(|e| e.context(format!("invalid base config {}", dir.base.display())))?;
+
+        let mut candidates = vec![];
+        for path in dir.fragments {
+            let fragment = read_file::<Fragment>(&path, ConfigFormat::from_path(&path))
+                .await
+                .and_then(|fragment| {
+                    let as_config = Config {
+                        mappings: fragment.mappings.clone(),
+                        ..Default::default()
+                    };
+                    as_config.validate()?;
+                    Ok(fragment.mappings)
+                });
+            candidates.push((path, fragment));
+        }
+
+        let host = bind_addr::host_addresses()?;
+        let addresses =
+            |selectors: &[String]| bind_addr::resolve(selectors, &host, self.bind_loopback);
+        let bind_addrs = config.bind_addrs.clone();
+        let claim = |mappings: &[Mapping], owner: &str| {
+            owned_listeners(mappings, owner, &bind_addrs, &addresses)
+        };
+        let taken = |listeners: &[(SocketAddrV4, String)], mappings: &[Mapping]| {
+            listener_taken(listeners, mappings, &bind_addrs, &addresses)
+        };
+        let mut listeners = claim(&config.mappings, &dir.base.display().to_string());
+        let mut last_good = self.fragments.lock().unwrap();
+        // fragments accepted before keep their ports, so a new or changed fragment that sorts
+        // first can't take them over
+        let mut kept = HashMap::new();
+        for (path, _) in &candidates {
+            let previous = last_good
+                .get(path)
+                .filter(|previous| taken(&listeners, previous).is_none());
+            if let Some(previous) = previous {
+                listeners.extend(claim(previous, &path.display().to_string()));
+                kept.insert(path.clone(), previous.clone());
+            }
+        }
+        let mut accepted = HashMap::new();
+        for (path, fragment) in candidates {
+            let owner = path.display().to_string();
+            listeners.retain(|(_, used_by)| *used_by != owner);
+            let fragment = fragment.and_then(|mappings| match taken(&listeners, &mappings) {
+                Some(conflict) => Err(anyhow::anyhow!(conflict)),
+                None => Ok(mappings),
+            });
+            let mappings = match (fragment, kept.remove(&path)) {
+                (Ok(mappings), _) => mappings,
+                (Err(e), Some(previous)) => {
+                    tracing::warn!(
+                        "rejected config fragment {}, keeping its last good version: {:#}",
+                        path.display(),
+                        e
+                    );
+                    previous
+                }
+                (Err(e), None) => {
+                    tracing::warn!("rejected config fragment {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            listeners.extend(claim(&mappings, &owner));
+            config.mappings.extend(mappings.iter().cloned());
+            accepted.insert(path, mappings);
+        }
+        *last_good = accepted;
+        Ok(config)
+    }
+}
+
+async fn read_file<T: DeserializeOwned>(path: &Path, format: ConfigFormat) -> anyhow::Result<T> {
+    let bytes = tokio::fs::read(path).await?;
+    format
+        .parse(&bytes)
+        .map_err(|e| e.context(format!("failed to parse {}", path.display())))
 }
 
 #[async_trait]
 impl ConfigProvider for FileConfigProvider {
     async fn read_config(&self) -> anyhow::Result<(Config, Vec<IntMapping>)> {
-        let config_bytes = tokio::fs::read(&self.config_path).await?;
-        let mut config: Config = serde_json::from_slice(&config_bytes)?;
-        if config.bind_addrs.is_empty() {
-            config.bind_addrs = crate::bind_addr::get_bind_addresses(self.bind_loopback)
-                .await?
-                .iter()
-                .map(|a| a.to_string())
-                .collect();
+        let config = if tokio::fs::metadata(&self.config_path).await?.is_dir() {
+            self.read_dir().await?
+        } else {
+            let config: Config = read_file(&self.config_path, self.format()).await?;
+            config
+                .validate()
+                .map_err(|e| e.context(format!("invalid config {}", self.config_path.display())))?;
+            config
+        };
+        config.resolve(self.bind_loopback).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "transparent": true,
+        "manage_iptables": false,
+        "should_exit": false,
+        "mappings": [
+            {"local_port": 2001, "target_address": "10.0.0.1:80", "hairpin_net": "10.0.0.0/24"}
+        ]
+    }"#;
+
+    const TOML: &str = r#"
+        # comments are the point
+        transparent = true
+        manage_iptables = false
+        should_exit = false
+
+        [[mappings]]
+        local_port = 2001
+        target_address = "10.0.0.1:80"
+        hairpin_net = "10.0.0.0/24"
+    "#;
+
+    const YAML: &str = r#"
+# comments are the point
+transparent: true
+manage_iptables: false
+should_exit: false
+mappings:
+  - local_port: 2001
+    target_address: "10.0.0.1:80"
+    hairpin_net: "10.0.0.0/24"
+"#;
+
+    #[test]
+    fn formats_are_equivalent() {
+        let json: Config = ConfigFormat::Json.parse(JSON.as_bytes()).unwrap();
+        let toml: Config = ConfigFormat::Toml.parse(TOML.as_bytes()).unwrap();
+        let yaml: Config = ConfigFormat::Yaml.parse(YAML.as_bytes()).unwrap();
+        assert_eq!(json, toml);
+        assert_eq!(json, yaml);
+    }
+
+    #[test]
+    fn parse_errors_report_position() {
+        let toml_err = ConfigFormat::Toml
+            .parse::<Config>(b"transparent = true\nmanage_iptables = nope\n")
+            .unwrap_err();
+        assert!(toml_err.to_string().contains("line 2"), "{toml_err}");
+        let yaml_err = ConfigFormat::Yaml
+            .parse::<Config>(b"transparent: true\nmanage_iptables: [\n")
+            .unwrap_err();
+        assert!(yaml_err.to_string().contains("line 2"), "{yaml_err}");
+        let json_err = ConfigFormat::Json
+            .parse::<Config>(b"{\n\"transparent\": tru }")
+            .unwrap_err();
+        assert!(json_err.to_string().contains("line 2"), "{json_err}");
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a/stargate.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("stargate.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("stargate.conf")),
+            ConfigFormat::Json
+        );
+    }
+
+    #[test]
+    fn mappings_may_share_a_port_on_different_addresses() {
+        let config: Config = ConfigFormat::Yaml
+            .parse(
+                br#"
+transparent: false
+manage_iptables: false
+should_exit: false
+bind_addrs: ["127.0.0.1"]
+mappings:
+  - local_port: 2001
+    target_address: "10.0.0.1:80"
+  - local_port: 2001
+    target_address: "10.0.0.2:80"
+    bind_addrs: ["127.0.0.2"]
+  - local_port: 2001
+    target_address: "10.0.0.3:80"
+    bind_addrs: ["127.0.0.2", "127.0.0.3"]
+  - local_port: 2002
+    target_address: "10.0.0.4:80"
+    bind_addrs: ["0.0.0.0"]
+  - local_port: 2002
+    target_address: "10.0.0.5:80"
+"#,
+            )
+            .unwrap();
+        let paths: Vec<String> = config.problems().into_iter().map(|p| p.path).collect();
+        assert_eq!(
+            paths,
+            vec!["$.mappings[2].local_port", "$.mappings[4].local_port"]
+        );
+    }
+
+    #[test]
+    fn mappings_override_global_transparency() {
+        let config: Config = ConfigFormat::Yaml
+            .parse(
+                br#"
+transparent: true
+manage_iptables: true
+should_exit: false
+bind_addrs: ["127.0.0.1"]
+mappings:
+  - local_port: 2001
+    target_address: "10.0.0.1:80"
+  - local_port: 2002
+    target_address: "10.0.0.2:80"
+    transparent: false
+  - local_port: 2003
+    target_address: "10.0.0.3:80"
+    manage_iptables: false
+"#,
+            )
+            .unwrap();
+        let modes: Vec<(bool, bool)> = config
+            .to_int_mappings()
+            .unwrap()
+            .iter()
+            .map(|m| (m.transparent, m.is_managed()))
+            .collect();
+        assert_eq!(modes, vec![(true, true), (false, false), (true, false)]);
+    }
+
+    #[test]
+    fn so_mark_applies_to_transparent_mappings() {
+        let config: Config = ConfigFormat::Toml
+            .parse(
+                br#"
+transparent = true
+manage_iptables = true
+should_exit = false
+return_routing = "so_mark"
+bind_addrs = ["127.0.0.1"]
+
+[[mappings]]
+local_port = 2001
+target_address = "10.0.0.1:80"
+
+[[mappings]]
+local_port = 2002
+target_address = "10.0.0.2:80"
+transparent = false
+"#,
+            )
+            .unwrap();
+        let mappings = config.to_int_mappings().unwrap();
+        assert_eq!(mappings[0].upstream_mark, Some(DEFAULT_UPSTREAM_MARK));
+        assert_eq!(mappings[0].return_rule_target(), None);
+        assert_eq!(mappings[1].upstream_mark, None);
+    }
+
+    #[test]
+    fn policy_routing_must_not_clash_with_upstream_mark() {
+        let config: Config = ConfigFormat::Toml
+            .parse(
+                br#"
+transparent = true
+manage_iptables = true
+should_exit = false
+return_routing = "so_mark"
+mappings = []
+
+[policy_routing]
+table = 200
+fwmark = 0x2
+fwmark_mask = 0xff
+"#,
+            )
+            .unwrap();
+        let paths: Vec<String> = config.problems().into_iter().map(|p| p.path).collect();
+        assert_eq!(paths, vec!["$.upstream_mark"]);
+    }
+
+    #[test]
+    fn intercepting_mappings() {
+        let config: Config = ConfigFormat::Toml
+            .parse(
+                br#"
+transparent = false
+manage_iptables = true
+should_exit = false
+bind_addrs = ["127.0.0.1"]
+
+[[mappings]]
+local_port = 3128
+mode = "tproxy"
+destinations = { nets = ["10.1.0.0/16"], ports = [80, 443] }
+
+[[mappings]]
+local_port = 3129
+mode = "redirect"
+destinations = { nets = ["10.2.0.0/16"] }
+
+[[mappings]]
+local_port = 3130
+mode = "tproxy"
+target_address = "10.0.0.1:80"
+"#,
+            )
+            .unwrap();
+        let paths: Vec<String> = config.problems().into_iter().map(|p| p.path).collect();
+        assert_eq!(
+            paths,
+            vec!["$.mappings[2].target_address", "$.mappings[2].destinations"]
+        );
+
+        let config = Config {
+            mappings: config.mappings[..2].to_vec(),
+            ..config
+        };
+        let mappings = config.to_int_mappings().unwrap();
+        // redirected connections go upstream from our own address unless asked otherwise
+        assert!(mappings[1].is_managed() && !mappings[1].transparent);
+        assert_eq!(
+            mappings[1].intercept().unwrap().upstream_mark,
+            Some(DEFAULT_UPSTREAM_MARK)
+        );
+        // redirected connections arrive on whichever address their interface has
+        assert_eq!(mappings[1].local_bind, "0.0.0.0:3129".parse().unwrap());
+        let mapping = &mappings[0];
+        assert!(mapping.is_managed());
+        assert_eq!(mapping.target_address, None);
+        assert_eq!(mapping.upstream_mark, Some(DEFAULT_UPSTREAM_MARK));
+        let intercept = mapping.intercept().unwrap();
+        assert!(intercept
+            .destinations
+            .matches(&"10.1.2.3:443".parse().unwrap()));
+        assert!(!intercept
+            .destinations
+            .matches(&"10.1.2.3:22".parse().unwrap()));
+        assert!(!intercept
+            .destinations
+            .matches(&"10.2.0.1:80".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn config_dir_rejects_bad_fragments_individually() {
+        let dir = std::env::temp_dir().join(format!("stargate-confd-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write(
+            "stargate.json",
+            r#"{"transparent": false, "manage_iptables": false, "should_exit": false,
+                "bind_addrs": ["127.0.0.1"],
+                "mappings": [{"local_port": 2000, "target_address": "10.0.0.1:80"}]}"#,
+        );
+        write(
+            "a.toml",
+            "[[mappings]]\nlocal_port = 2001\ntarget_address = \"10.0.0.2:80\"\n",
+        );
+        write(
+            "b.yaml",
+            "mappings:\n  - local_port: 2002\n    target_address: \"10.0.0.3:80\"\n",
+        );
+        let provider = FileConfigProvider::new(dir.clone(), None, false);
+        let ports = |config: &Config| -> Vec<u16> {
+            config.mappings.iter().map(|m| m.local_port).collect()
+        };
+
+        let (config, _) = provider.read_config().await.unwrap();
+        assert_eq!(ports(&config), vec![2000, 2001, 2002]);
+
+        // a valid change to one fragment applies even though another has become invalid, which
+        // keeps its last good mappings
+        write(
+            "a.toml",
+            "[[mappings]]\nlocal_port = 2003\ntarget_address = \"10.0.0.2:80\"\n",
+        );
+        write(
+            "b.yaml",
+            "mappings:\n  - local_port: 2002\n    target_address: \"nope\"\n",
+        );
+        write(
+            "c.json",
+            r#"{"mappings": [{"local_port": 2003, "target_address": "10.0.0.4:80"}]}"#,
+        );
+        let (config, _) = provider.read_config().await.unwrap();
+        assert_eq!(ports(&config), vec![2000, 2003, 2002]);
+
+        // a new fragment sorting before the one that owns a port doesn't take it over
+        write(
+            "0.json",
+            r#"{"mappings": [{"local_port": 2003, "target_address": "10.0.0.5:80"}]}"#,
+        );
+        let (config, _) = provider.read_config().await.unwrap();
+        assert_eq!(ports(&config), vec![2000, 2003, 2002]);
+        assert_eq!(
+            config.mappings[1].target_address.as_deref(),
+            Some("10.0.0.2:80")
+        );
+
+        // fragments may share a port on different addresses
+        for (name, addr) in [("d.json", "127.0.0.2"), ("e.json", "127.0.0.3")] {
+            write(
+                name,
+                &format!(
+                    r#"{{"mappings": [{{"local_port": 2004, "target_address": "10.0.0.6:80",
+                        "bind_addrs": ["{addr}"]}}]}}"#
+                ),
+            );
         }
-        config.bind_addrs.sort();
+        let (config, _) = provider.read_config().await.unwrap();
+        assert_eq!(ports(&config), vec![2000, 2003, 2002, 2004, 2004]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
 
-        let int_mappings = config.to_int_mappings()?;
-        Ok((config, int_mappings))
+    #[tokio::test]
+    async fn config_file_is_validated() {
+        let path = std::env::temp_dir().join(format!("stargate-{}.json", std::process::id()));
+        tokio::fs::write(
+            &path,
+            r#"{"transparent": false, "manage_iptables": false, "should_exit": false,
+                "bind_addrs": ["127.0.0.1"], "bind_retry_initial_secs": 0,
+                "mappings": [{"local_port": 2000, "target_address": "10.0.0.1:80"}]}"#,
+        )
+        .await
+        .unwrap();
+        let provider = FileConfigProvider::new(path.clone(), None, false);
+        let error = provider.read_config().await.err().unwrap();
+        assert!(
+            format!("{error:#}").contains("bind_retry_initial_secs must not be 0"),
+            "{error:#}"
+        );
+        tokio::fs::remove_file(&path).await.unwrap();
     }
 }
@@ -0,0 +1,236 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use tokio::sync::Mutex;
+
+use crate::config::{Config, ConfigProvider, IntMapping, Mapping};
+
+/// A `ConfigProvider` whose mappings are managed at runtime through an HTTP control API,
+/// optionally persisted to a state file so that a restart reproduces them.
+#[derive(Clone)]
+pub struct ApiConfigProvider {
+    config: Arc<Mutex<Config>>,
+    state_path: Option<PathBuf>,
+    bind_loopback: bool,
+}
+
+pub enum ApiError {
+    NotFound(String),
+    Invalid(anyhow::Error),
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::Invalid(e) => (StatusCode::BAD_REQUEST, format!("{e:#}")),
+            ApiError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+impl ApiConfigProvider {
+    pub async fn new(state_path: Option<PathBuf>, bind_loopback: bool) -> anyhow::Result<Self> {
+        let config = match &state_path {
+            Some(path) if tokio::fs::try_exists(path).await? => {
+                let config: Config = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+                config.validate()?;
+                tracing::info!(
+                    "restored {} mappings from {:?}",
+                    config.mappings.len(),
+                    path
+                );
+                config
+            }
+            _ => Config::default(),
+        };
+        Ok(ApiConfigProvider {
+            config: Arc::new(Mutex::new(config)),
+            state_path,
+            bind_loopback,
+        })
+    }
+
+    /// Validates and persists `new_config`, then makes it the current config.
+    async fn apply(&self, current: &mut Config, new_config: Config) -> Result<(), ApiError> {
+        new_config.validate().map_err(ApiError::Invalid)?;
+        if let Some(path) = &self.state_path {
+            let tmp_path = path.with_extension("tmp");
+            let bytes =
+                serde_json::to_vec_pretty(&new_config).map_err(|e| ApiError::Internal(e.into()))?;
+            tokio::fs::write(&tmp_path, bytes)
+                .await
+                .map_err(|e| ApiError::Internal(e.into()))?;
+            tokio::fs::rename(&tmp_path, path)
+                .await
+                .map_err(|e| ApiError::Internal(e.into()))?;
+        }
+        *current = new_config;
+        Ok(())
+    }
+
+    pub async fn get_config(&self) -> Config {
+        self.config.lock().await.clone()
+    }
+
+    pub async fn replace_config(&self, new_config: Config) -> Result<(), ApiError> {
+        let mut current = self.config.lock().await;
+        self.apply(&mut current, new_config).await
+    }
+
+    /// Adds the mapping, or replaces the existing mapping with the same id, see `Mapping::id`.
+    pub async fn upsert_mapping(&self, mapping: Mapping) -> Result<(), ApiError> {
+        let mut current = self.config.lock().await;
+        let mut new_config = current.clone();
+        let id = mapping.id();
+        new_config.mappings.retain(|m| m.id() != id);
+        new_config.mappings.push(mapping);
+        new_config.mappings.sort_by_key(|m| (m.local_port, m.id()));
+        self.apply(&mut current, new_config).await
+    }
+
+    pub async fn remove_mapping(&self, id: &str) -> Result<(), ApiError> {
+        let mut current = self.config.lock().await;
+        let mut new_config = current.clone();
+        new_config.mappings.retain(|m| m.id() != id);
+        if new_config.mappings.len() == current.mappings.len() {
+            return Err(ApiError::NotFound(format!("no mapping {id}")));
+        }
+        self.apply(&mut current, new_config).await
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> anyhow::Result<()> {
+        let app = Router::new()
+            .route("/config", get(get_config).put(put_config))
+            .route("/mappings", get(get_mappings))
+            .route(
+                "/mappings/:id",
+                get(get_mapping).put(put_mapping).delete(delete_mapping),
+            )
+            .with_state(self);
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tracing::info!("serving control API on http://{addr}");
+        axum::serve(listener, app).await?;
+        Ok(())
+    }
+}
+
+async fn get_config(State(provider): State<ApiConfigProvider>) -> Json<Config> {
+    Json(provider.get_config().await)
+}
+
+async fn put_config(
+    State(provider): State<ApiConfigProvider>,
+    Json(config): Json<Config>,
+) -> Result<StatusCode, ApiError> {
+    provider.replace_config(config).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_mappings(State(provider): State<ApiConfigProvider>) -> Json<Vec<Mapping>> {
+    Json(provider.get_config().await.mappings)
+}
+
+async fn get_mapping(
+    State(provider): State<ApiConfigProvider>,
+    Path(id): Path<String>,
+) -> Result<Json<Mapping>, ApiError> {
+    provider
+        .get_config()
+        .await
+        .mappings
+        .into_iter()
+        .find(|m| m.id() == id)
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("no mapping {id}")))
+}
+
+async fn put_mapping(
+    State(provider): State<ApiConfigProvider>,
+    Path(id): Path<String>,
+    Json(mapping): Json<Mapping>,
+) -> Result<StatusCode, ApiError> {
+    if mapping.id() != id {
+        return Err(ApiError::Invalid(anyhow::anyhow!(
+            "mapping {} in body does not match {id} in path",
+            mapping.id()
+        )));
+    }
+    provider.upsert_mapping(mapping).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_mapping(
+    State(provider): State<ApiConfigProvider>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    provider.remove_mapping(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[async_trait]
+impl ConfigProvider for ApiConfigProvider {
+    async fn read_config(&self) -> anyhow::Result<(Config, Vec<IntMapping>)> {
+        self.get_config().await.resolve(self.bind_loopback).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(json: &str) -> Mapping {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn mappings_are_validated_and_persisted() {
+        let path = std::env::temp_dir().join(format!("stargate-api-{}.json", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+        let provider = ApiConfigProvider::new(Some(path.clone()), false)
+            .await
+            .unwrap();
+        let web = r#"{"name": "web", "local_port": 8080, "target_address": "10.0.0.1:80"}"#;
+        assert!(provider.upsert_mapping(mapping(web)).await.is_ok());
+        // unnamed mappings on the same port but different addresses are kept apart
+        for addr in ["127.0.0.2", "127.0.0.3"] {
+            let json = format!(
+                r#"{{"local_port": 8081, "target_address": "10.0.0.2:80",
+                    "bind_addrs": ["{addr}"]}}"#
+            );
+            assert!(provider.upsert_mapping(mapping(&json)).await.is_ok());
+        }
+        assert!(provider.remove_mapping("127.0.0.3:8081").await.is_ok());
+        let persisted = tokio::fs::read(&path).await.unwrap();
+
+        let invalid = r#"{"name": "web", "local_port": 0, "target_address": "10.0.0.1:80"}"#;
+        match provider.upsert_mapping(mapping(invalid)).await {
+            Err(ApiError::Invalid(e)) => {
+                assert!(e.to_string().contains("local_port must not be 0"), "{e:#}")
+            }
+            _ => panic!("invalid mapping accepted"),
+        }
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), persisted);
+
+        // a restart picks up where the last run left off
+        let restarted = ApiConfigProvider::new(Some(path.clone()), false)
+            .await
+            .unwrap();
+        let config = restarted.get_config().await;
+        assert_eq!(config, provider.get_config().await);
+        let ids: Vec<String> = config.mappings.iter().map(|m| m.id()).collect();
+        assert_eq!(ids, vec!["web", "127.0.0.2:8081"]);
+        assert_eq!(config.mappings[0].local_port, 8080);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
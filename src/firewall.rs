@@ -0,0 +1,157 @@
+use std::collections::BTreeSet;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::config::{FirewallBackendKind, IntMapping, Intercept, PolicyRouting};
+use crate::iptables_setup::IptablesBackend;
+use crate::nftables::NftablesBackend;
+use crate::plan::Operation;
+
+/// Every rule a backend installs.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Rules {
+    /// Upstream marks needing connmark rules.
+    pub marks: BTreeSet<u32>,
+    /// Targets needing return rules.
+    pub targets: BTreeSet<SocketAddrV4>,
+    pub intercepts: BTreeSet<Intercept>,
+}
+
+/// Return rules and intercepts to add and remove in one go.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Changes {
+    pub add_targets: BTreeSet<SocketAddrV4>,
+    pub del_targets: BTreeSet<SocketAddrV4>,
+    pub add_intercepts: BTreeSet<Intercept>,
+    pub del_intercepts: BTreeSet<Intercept>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.add_targets.is_empty()
+            && self.del_targets.is_empty()
+            && self.add_intercepts.is_empty()
+            && self.del_intercepts.is_empty()
+    }
+}
+
+/// Installs the packet marking rules that steer replies from targets back through the policy
+/// routing set up by `iptables_setup::initial_setup`, using the mark it is configured with, and
+/// the rules diverting intercepted traffic to listeners.
+#[async_trait]
+pub trait FirewallBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn kind(&self) -> FirewallBackendKind;
+
+    /// Marks packets coming from `target`.
+    async fn add_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()>;
+
+    async fn del_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()>;
+
+    /// Saves `mark` from upstream sockets to their connections, and marks replies on them.
+    async fn add_connmark_rules(&self, mark: u32) -> anyhow::Result<()>;
+
+    /// Diverts the traffic of `intercept` to its listener.
+    async fn add_intercept(&self, intercept: &Intercept) -> anyhow::Result<()>;
+
+    async fn del_intercept(&self, intercept: &Intercept) -> anyhow::Result<()>;
+
+    /// Makes all of `changes`, by default one rule at a time, carrying on past failures.
+    async fn apply_changes(&self, changes: &Changes) -> anyhow::Result<()> {
+        let mut errors = vec![];
+        for target in &changes.del_targets {
+            if let Err(e) = self.del_return_rule(*target).await {
+                errors.push(format!("removing return rule for {target}: {e:#}"));
+            }
+        }
+        for intercept in &changes.del_intercepts {
+            if let Err(e) = self.del_intercept(intercept).await {
+                errors.push(format!(
+                    "removing intercept for {}: {e:#}",
+                    intercept.listener
+                ));
+            }
+        }
+        for target in &changes.add_targets {
+            if let Err(e) = self.add_return_rule(*target).await {
+                errors.push(format!("adding return rule for {target}: {e:#}"));
+            }
+        }
+        for intercept in &changes.add_intercepts {
+            if let Err(e) = self.add_intercept(intercept).await {
+                errors.push(format!(
+                    "adding intercept for {}: {e:#}",
+                    intercept.listener
+                ));
+            }
+        }
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Makes the installed rules exactly `rules`, removing any left behind by a previous run or
+    /// added back any removed by someone else. Returns how many rules were missing or stale.
+    async fn reconcile(&self, rules: &Rules) -> anyhow::Result<usize>;
+
+    /// Removes every rule this backend has installed.
+    async fn teardown(&self) -> anyhow::Result<()>;
+
+    /// The operations that installing `rules` would perform.
+    fn plan(&self, rules: &Rules) -> Vec<Operation>;
+}
+
+/// The rules needed by the managed mappings among `int_mappings`.
+pub fn wanted_rules(int_mappings: &[IntMapping]) -> Rules {
+    let managed = || int_mappings.iter().filter(|m| m.is_managed());
+    Rules {
+        marks: managed()
+            .filter(|m| m.transparent)
+            .filter_map(|m| m.upstream_mark)
+            .collect(),
+        targets: managed().filter_map(|m| m.return_rule_target()).collect(),
+        intercepts: managed().filter_map(|m| m.intercept()).collect(),
+    }
+}
+
+/// The backend for `kind`, detecting which one the host uses for `FirewallBackendKind::Auto`.
+pub async fn backend(
+    kind: FirewallBackendKind,
+    routing: PolicyRouting,
+) -> Arc<dyn FirewallBackend> {
+    let kind = match kind {
+        FirewallBackendKind::Auto => detect().await,
+        kind => kind,
+    };
+    match kind {
+        FirewallBackendKind::Nftables => Arc::new(NftablesBackend::new(routing)),
+        _ => Arc::new(IptablesBackend { routing }),
+    }
+}
+
+async fn detect() -> FirewallBackendKind {
+    let nft = version("nft").await.is_some();
+    let iptables = version("iptables").await;
+    tracing::debug!("nft present: {nft}, iptables version: {iptables:?}");
+    match iptables {
+        Some(version) if version.contains("legacy") => FirewallBackendKind::Iptables,
+        _ if nft => FirewallBackendKind::Nftables,
+        _ => FirewallBackendKind::Iptables,
+    }
+}
+
+async fn version(binary: &str) -> Option<String> {
+    let output = tokio::process::Command::new(binary)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,271 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::{Config, ConfigFormat, ConfigProvider, IntMapping};
+
+/// Longest wait between attempts while the config endpoint keeps failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Where and how to fetch the config from.
+pub struct HttpSource {
+    pub url: reqwest::Url,
+    pub format: ConfigFormat,
+    pub bearer_token: Option<String>,
+    /// How often to poll the endpoint once it has answered successfully.
+    pub poll_interval: Duration,
+    /// Limit for each whole request, including reading the body.
+    pub timeout: Duration,
+    /// File to keep the last known-good config in, used when starting while the endpoint
+    /// is unreachable.
+    pub cache_path: Option<PathBuf>,
+}
+
+/// A `ConfigProvider` that polls an HTTP(S) endpoint for the config, and falls back to the
+/// last known-good config while the endpoint is unreachable or serving an invalid config.
+pub struct HttpConfigProvider {
+    client: reqwest::Client,
+    source: HttpSource,
+    bind_loopback: bool,
+    state: Mutex<PollState>,
+}
+
+#[derive(Default)]
+struct PollState {
+    etag: Option<String>,
+    last_good: Option<Config>,
+    next_poll: Option<Instant>,
+    failures: u32,
+}
+
+/// What the disk cache holds: the last known-good config and the ETag it was served with.
+#[derive(Serialize, Deserialize)]
+struct CachedConfig {
+    etag: Option<String>,
+    config: Config,
+}
+
+enum Fetched {
+    NotModified,
+    Config(Box<Config>, Option<String>),
+}
+
+impl HttpConfigProvider {
+    pub async fn new(source: HttpSource, bind_loopback: bool) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(source.timeout)
+            .connect_timeout(source.timeout)
+            .build()?;
+        let mut state = PollState::default();
+        if let Some(path) = &source.cache_path {
+            if tokio::fs::try_exists(path).await? {
+                let cached: CachedConfig = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+                cached.config.validate()?;
+                tracing::info!("loaded cached config from {:?}", path);
+                state.etag = cached.etag;
+                state.last_good = Some(cached.config);
+            }
+        }
+        Ok(HttpConfigProvider {
+            client,
+            source,
+            bind_loopback,
+            state: Mutex::new(state),
+        })
+    }
+
+    async fn fetch(&self, etag: Option<&str>) -> anyhow::Result<Fetched> {
+        let mut request = self.client.get(self.source.url.clone());
+        if let Some(token) = &self.source.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+        let response = response.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let config: Config = self.source.format.parse(&response.bytes().await?)?;
+        config.validate()?;
+        Ok(Fetched::Config(Box::new(config), etag))
+    }
+
+    async fn write_cache(&self, cached: &CachedConfig) -> anyhow::Result<()> {
+        if let Some(path) = &self.source.cache_path {
+            let tmp_path = path.with_extension("tmp");
+            tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(cached)?).await?;
+            tokio::fs::rename(&tmp_path, path).await?;
+        }
+        Ok(())
+    }
+
+    /// Polls the endpoint if it is due, and returns the config to run.
+    async fn current_config(&self) -> anyhow::Result<Config> {
+        let mut state = self.state.lock().await;
+        if let (Some(next_poll), Some(last_good)) = (state.next_poll, &state.last_good) {
+            if Instant::now() < next_poll {
+                return Ok(last_good.clone());
+            }
+        }
+        let etag = state.etag.clone();
+        match self.fetch(etag.as_deref()).await {
+            Ok(fetched) => {
+                state.failures = 0;
+                state.next_poll = Some(Instant::now() + self.source.poll_interval);
+                if let Fetched::Config(config, etag) = fetched {
+                    let config = *config;
+                    if state.last_good.as_ref() != Some(&config) {
+                        tracing::info!("fetched new config from {}", self.source.url);
+                        let cached = CachedConfig { etag, config };
+                        if let Err(e) = self.write_cache(&cached).await {
+                            tracing::warn!("failed to write config cache: {:#}", e);
+                        }
+                        state.last_good = Some(cached.config);
+                        state.etag = cached.etag;
+                    } else {
+                        state.etag = etag;
+                    }
+                }
+            }
+            Err(e) => {
+                let backoff = self
+                    .source
+                    .poll_interval
+                    .saturating_mul(2u32.saturating_pow(state.failures))
+                    .min(MAX_BACKOFF);
+                state.failures += 1;
+                state.next_poll = Some(Instant::now() + backoff);
+                match &state.last_good {
+                    Some(_) => tracing::warn!(
+                        "failed to fetch config from {}, keeping last known-good config, \
+                         retrying in {:?}: {:#}",
+                        self.source.url,
+                        backoff,
+                        e
+                    ),
+                    None => {
+                        return Err(
+                            e.context(format!("failed to fetch config from {}", self.source.url))
+                        )
+                    }
+                }
+            }
+        }
+        Ok(state.last_good.clone().unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl ConfigProvider for HttpConfigProvider {
+    async fn read_config(&self) -> anyhow::Result<(Config, Vec<IntMapping>)> {
+        self.current_config()
+            .await?
+            .resolve(self.bind_loopback)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const CONFIG: &str = r#"{"transparent": false, "manage_iptables": false,
+        "should_exit": false, "bind_addrs": ["127.0.0.1"],
+        "mappings": [{"local_port": 2001, "target_address": "10.0.0.1:80"}]}"#;
+
+    /// Serves `CONFIG` with an ETag to requests carrying the right token, and counts the
+    /// responses that had a body.
+    async fn stand_in(full_responses: Arc<AtomicUsize>) -> reqwest::Url {
+        let app = Router::new().route(
+            "/config",
+            get(move |headers: HeaderMap| async move {
+                if headers.get("authorization").map(|v| v.as_bytes()) != Some(b"Bearer s3cret") {
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                if headers.get("if-none-match").map(|v| v.as_bytes()) == Some(b"\"v1\"") {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                full_responses.fetch_add(1, Ordering::SeqCst);
+                ([("etag", "\"v1\"")], CONFIG).into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/config").parse().unwrap()
+    }
+
+    fn source(url: reqwest::Url, cache_path: Option<PathBuf>) -> HttpSource {
+        HttpSource {
+            url,
+            format: ConfigFormat::Json,
+            bearer_token: Some("s3cret".to_string()),
+            poll_interval: Duration::ZERO,
+            timeout: Duration::from_secs(2),
+            cache_path,
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_config_is_not_resent() {
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let url = stand_in(full_responses.clone()).await;
+        let provider = HttpConfigProvider::new(source(url, None), false)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            let (config, mappings) = provider.read_config().await.unwrap();
+            assert_eq!(config.mappings.len(), 1);
+            assert_eq!(mappings.len(), 1);
+        }
+        assert_eq!(full_responses.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_cached_config_when_unreachable() {
+        let cache_path =
+            std::env::temp_dir().join(format!("stargate-http-cache-{}.json", std::process::id()));
+        let url = stand_in(Arc::new(AtomicUsize::new(0))).await;
+        let provider = HttpConfigProvider::new(source(url, Some(cache_path.clone())), false)
+            .await
+            .unwrap();
+        let (fetched, _) = provider.read_config().await.unwrap();
+
+        let unreachable = format!(
+            "http://127.0.0.1:{}/config",
+            portpicker::pick_unused_port().unwrap()
+        );
+        let provider = HttpConfigProvider::new(
+            source(unreachable.parse().unwrap(), Some(cache_path.clone())),
+            false,
+        )
+        .await
+        .unwrap();
+        let (cached, _) = provider.read_config().await.unwrap();
+        tokio::fs::remove_file(&cache_path).await.unwrap();
+        assert_eq!(fetched, cached);
+
+        let provider = HttpConfigProvider::new(source(unreachable.parse().unwrap(), None), false)
+            .await
+            .unwrap();
+        assert!(provider.read_config().await.is_err());
+    }
+}
@@ -1,5 +1,12 @@
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddrV4;
 
+use async_trait::async_trait;
+
+use crate::config::{FirewallBackendKind, Intercept, MappingMode, PolicyRouting};
+use crate::firewall::{FirewallBackend, Rules};
+use crate::plan::Operation;
+
 cfg_if! {
     if #[cfg(target_os = "linux")] {
         use anyhow::{anyhow, bail};
@@ -8,53 +15,197 @@ cfg_if! {
         use netlink_packet_route::route::Nla as RouteNla;
         use netlink_packet_route::rule::Nla as RuleNla;
         use netlink_packet_route::{ARPHRD_LOOPBACK, FR_ACT_TO_TBL, RTN_LOCAL, RTPROT_BOOT, RT_SCOPE_HOST};
+        use netlink_packet_route::{RouteMessage, RuleMessage};
         use rtnetlink;
         use rtnetlink::{Handle, IpVersion};
+        use std::collections::{HashMap, HashSet};
     } else {
     }
 }
 
+/// Routing table that marked return traffic is looked up in, unless configured otherwise.
+pub const ROUTING_TABLE: u32 = 100;
+/// Firewall mark applied to return traffic so that it is routed via the routing table, unless
+/// configured otherwise.
+pub const FWMARK: u32 = 1;
+pub const FWMARK_MASK: u32 = 0xffffffff;
+
+/// The parts of an `ip rule` that tell whether it is ours or gets in our way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingRule {
+    pub priority: u32,
+    /// The table looked up, if the rule's action is a lookup.
+    pub lookup: Option<u32>,
+    /// The mark and mask matched, if the rule matches on a mark.
+    pub fwmark: Option<(u32, u32)>,
+}
+
+impl Display for ExistingRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rule {}:", self.priority)?;
+        if let Some((mark, mask)) = self.fwmark {
+            write!(f, " fwmark {mark:#x}/{mask:#x}")?;
+        }
+        match self.lookup {
+            Some(table) => write!(f, " lookup {table}"),
+            None => write!(f, " (not a lookup)"),
+        }
+    }
+}
+
+impl ExistingRule {
+    fn is_ours(&self, routing: &PolicyRouting) -> bool {
+        self.lookup == Some(routing.table)
+            && self.fwmark == Some((routing.fwmark, routing.fwmark_mask))
+    }
+}
+
+/// Why `rule` gets in the way of `routing`, if it does: it uses the same table, matches marks
+/// that ours matches too, or takes the configured priority. Our own rule at a different
+/// priority than configured counts too, rather than silently being reused.
+pub fn rule_conflict(routing: &PolicyRouting, rule: &ExistingRule) -> Option<String> {
+    if rule.is_ours(routing) {
+        return match routing.priority {
+            Some(priority) if priority != rule.priority => Some(format!(
+                "{rule} is already present but not at priority {priority}"
+            )),
+            _ => None,
+        };
+    }
+    if rule.lookup == Some(routing.table) {
+        return Some(format!(
+            "table {} is already looked up by {rule}",
+            routing.table
+        ));
+    }
+    if let Some((mark, mask)) = rule.fwmark {
+        if (mark ^ routing.fwmark) & mask & routing.fwmark_mask == 0 {
+            return Some(format!(
+                "fwmark {:#x}/{:#x} overlaps with {rule}",
+                routing.fwmark, routing.fwmark_mask
+            ));
+        }
+    }
+    if routing.priority == Some(rule.priority) {
+        return Some(format!(
+            "priority {} is already taken by {rule}",
+            rule.priority
+        ));
+    }
+    None
+}
+
 #[cfg(target_os = "linux")]
-async fn find_ip_rule(handle: &Handle) -> anyhow::Result<bool> {
+fn existing_rule(msg: &RuleMessage) -> ExistingRule {
+    let mut table = msg.header.table as u32;
+    let mut priority = 0;
+    let mut fwmark = None;
+    let mut fwmask = FWMARK_MASK;
+    for nla in &msg.nlas {
+        match nla {
+            RuleNla::Table(t) => table = *t,
+            RuleNla::Priority(p) => priority = *p,
+            RuleNla::FwMark(m) => fwmark = Some(*m),
+            RuleNla::FwMask(m) => fwmask = *m,
+            _ => {}
+        }
+    }
+    ExistingRule {
+        priority,
+        lookup: (msg.header.action == FR_ACT_TO_TBL).then_some(table),
+        fwmark: fwmark.map(|mark| (mark, fwmask)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn route_table(msg: &RouteMessage) -> u32 {
+    msg.nlas
+        .iter()
+        .find_map(|nla| match nla {
+            RouteNla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(msg.header.table as u32)
+}
+
+#[cfg(target_os = "linux")]
+fn is_our_route(msg: &RouteMessage) -> bool {
+    msg.header.scope == RT_SCOPE_HOST
+        && msg.header.kind == RTN_LOCAL
+        && msg.nlas.iter().any(|nla| matches!(nla, RouteNla::Oif(1)))
+}
+
+#[cfg(target_os = "linux")]
+async fn find_ip_rule_msg(
+    handle: &Handle,
+    routing: &PolicyRouting,
+) -> anyhow::Result<Option<RuleMessage>> {
     let mut r = handle.rule().get(IpVersion::V4).execute();
-    let mut found_rule = false;
+    let mut found_rule = None;
     while let Some(msg) = r.try_next().await? {
-        if msg.header.table == 100 && msg.header.action == FR_ACT_TO_TBL {
-            for nla in &msg.nlas {
-                match nla {
-                    RuleNla::FwMark(1) => {
-                        found_rule = true;
-                    }
-                    _ => {}
-                }
-            }
+        if existing_rule(&msg).is_ours(routing) {
+            found_rule = Some(msg);
         }
     }
     Ok(found_rule)
 }
 
 #[cfg(target_os = "linux")]
-async fn find_ip_route(handle: &Handle) -> anyhow::Result<bool> {
+async fn find_ip_rule(handle: &Handle, routing: &PolicyRouting) -> anyhow::Result<bool> {
+    Ok(find_ip_rule_msg(handle, routing).await?.is_some())
+}
+
+#[cfg(target_os = "linux")]
+async fn find_ip_route_msg(
+    handle: &Handle,
+    routing: &PolicyRouting,
+) -> anyhow::Result<Option<RouteMessage>> {
     let mut r = handle.route().get(IpVersion::V4).execute();
-    let mut found_route = false;
+    let mut found_route = None;
     while let Some(msg) = r.try_next().await? {
-        if msg.header.table == 100
-            && msg.header.scope == RT_SCOPE_HOST
-            && msg.header.kind == RTN_LOCAL
-        {
-            for nla in &msg.nlas {
-                match nla {
-                    RouteNla::Oif(1) => {
-                        found_route = true;
-                    }
-                    _ => {}
-                }
-            }
+        if route_table(&msg) == routing.table && is_our_route(&msg) {
+            found_route = Some(msg);
         }
     }
     Ok(found_route)
 }
 
+#[cfg(target_os = "linux")]
+async fn find_ip_route(handle: &Handle, routing: &PolicyRouting) -> anyhow::Result<bool> {
+    Ok(find_ip_route_msg(handle, routing).await?.is_some())
+}
+
+/// Everything already on the host that stops `routing` being set up as configured.
+#[cfg(target_os = "linux")]
+async fn find_conflicts(handle: &Handle, routing: &PolicyRouting) -> anyhow::Result<Vec<String>> {
+    let mut conflicts = vec![];
+    let mut r = handle.rule().get(IpVersion::V4).execute();
+    while let Some(msg) = r.try_next().await? {
+        conflicts.extend(rule_conflict(routing, &existing_rule(&msg)));
+    }
+    let mut r = handle.route().get(IpVersion::V4).execute();
+    let mut foreign_routes = 0;
+    while let Some(msg) = r.try_next().await? {
+        if route_table(&msg) == routing.table && !is_our_route(&msg) {
+            foreign_routes += 1;
+        }
+    }
+    if foreign_routes > 0 {
+        conflicts.push(format!(
+            "table {} already holds {foreign_routes} other route(s)",
+            routing.table
+        ));
+    }
+    Ok(conflicts)
+}
+
+#[cfg(target_os = "linux")]
+pub async fn routing_conflicts(routing: &PolicyRouting) -> anyhow::Result<Vec<String>> {
+    let (connection, handle, _) = rtnetlink::new_connection()?;
+    tokio::spawn(connection);
+    find_conflicts(&handle, routing).await
+}
+
 #[cfg(target_os = "linux")]
 async fn find_loopback_intf(handle: &Handle) -> anyhow::Result<u32> {
     let mut r = handle.link().get().execute();
@@ -96,47 +247,70 @@ async fn find_loopback_intf(handle: &Handle) -> anyhow::Result<u32> {
     bail! {"loopback interface not found"};
 }
 
+/// Adds the policy routing rule and route that marked traffic is delivered locally by, setting
+/// in `created` which of the two it created as opposed to finding them already in place. It is
+/// set as each is added, so that it is right even when adding the other fails.
 #[cfg(target_os = "linux")]
-pub async fn initial_setup() -> anyhow::Result<()> {
+pub async fn initial_setup(
+    routing: &PolicyRouting,
+    created: &mut (bool, bool),
+) -> anyhow::Result<()> {
     let (connection, handle, _) = rtnetlink::new_connection()?;
     tokio::spawn(connection);
 
+    let conflicts = find_conflicts(&handle, routing).await?;
+    if !conflicts.is_empty() {
+        bail!(
+            "policy routing conflicts with the host's: {}",
+            conflicts.join("; ")
+        );
+    }
     let lo_idx = find_loopback_intf(&handle).await?;
 
-    if find_ip_rule(&handle).await? {
-        tracing::info!("ip rule already exists, skipping creation");
+    if find_ip_rule(&handle, routing).await? {
+        tracing::debug!("ip rule already exists, skipping creation");
     } else {
-        handle
+        let mut request = handle
             .rule()
             .add()
-            .table_id(100)
+            .table_id(routing.table)
             .action(FR_ACT_TO_TBL)
             .v4()
-            .fw_mark(1)
-            .execute()
-            .await?;
-        if find_ip_rule(&handle).await? {
+            .fw_mark(routing.fwmark);
+        if let Some(priority) = routing.priority {
+            request = request.priority(priority);
+        }
+        if routing.fwmark_mask != FWMARK_MASK {
+            request
+                .message_mut()
+                .nlas
+                .push(RuleNla::FwMask(routing.fwmark_mask));
+        }
+        request.execute().await?;
+        created.0 = true;
+        if find_ip_rule(&handle, routing).await? {
             tracing::info!("Rule added successfully");
         } else {
             bail!("Rule not found after successful add");
         }
     }
 
-    if find_ip_route(&handle).await? {
-        eprint!("ip route already exists, skipping creation");
+    if find_ip_route(&handle, routing).await? {
+        tracing::debug!("ip route already exists, skipping creation");
     } else {
         handle
             .route()
             .add()
             .v4()
-            .table_id(100)
+            .table_id(routing.table)
             .output_interface(lo_idx)
             .scope(RT_SCOPE_HOST)
             .protocol(RTPROT_BOOT)
             .kind(RTN_LOCAL)
             .execute()
             .await?;
-        if find_ip_route(&handle).await? {
+        created.1 = true;
+        if find_ip_route(&handle, routing).await? {
             tracing::info!("Route added successfully");
         } else {
             bail!("Route not found after successful add")
@@ -146,57 +320,664 @@ pub async fn initial_setup() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Removes the policy routing rule if `rule` is set and the route if `route` is set, for undoing
+/// `initial_setup`.
 #[cfg(target_os = "linux")]
-const TABLE_MANGLE: &str = "mangle";
-#[cfg(target_os = "linux")]
-const CHAIN_PREROUTING: &str = "PREROUTING";
+pub async fn remove_routing(
+    routing: &PolicyRouting,
+    rule: bool,
+    route: bool,
+) -> anyhow::Result<()> {
+    let (connection, handle, _) = rtnetlink::new_connection()?;
+    tokio::spawn(connection);
 
-#[cfg(target_os = "linux")]
-fn gen_rule_str(target: SocketAddrV4) -> String {
+    if rule {
+        if let Some(msg) = find_ip_rule_msg(&handle, routing).await? {
+            handle.rule().del(msg).execute().await?;
+            tracing::info!("Rule removed");
+        }
+    }
+    if route {
+        if let Some(msg) = find_ip_route_msg(&handle, routing).await? {
+            handle.route().del(msg).execute().await?;
+            tracing::info!("Route removed");
+        }
+    }
+    Ok(())
+}
+
+pub const TABLE_MANGLE: &str = "mangle";
+pub const TABLE_NAT: &str = "nat";
+pub const CHAIN_PREROUTING: &str = "PREROUTING";
+pub const CHAIN_OUTPUT: &str = "OUTPUT";
+/// Chains of our own, jumped to from `CHAIN_PREROUTING` and `CHAIN_OUTPUT`, so that our rules can
+/// be listed and removed without looking at anyone else's.
+pub const CHAIN_STARGATE: &str = "STARGATE";
+pub const CHAIN_STARGATE_OUTPUT: &str = "STARGATE_OUTPUT";
+/// Chains of our own in the nat table, for REDIRECT rules.
+pub const CHAIN_STARGATE_NAT: &str = "STARGATE_NAT";
+pub const CHAIN_STARGATE_NAT_OUTPUT: &str = "STARGATE_NAT_OUTPUT";
+/// Every rule we create is tagged with a comment starting with this.
+pub const COMMENT_TAG: &str = "stargate";
+
+/// Our chains, each with its table and the built-in chain that jumps to it.
+pub const CHAINS: [(&str, &str, &str); 4] = [
+    (TABLE_MANGLE, CHAIN_PREROUTING, CHAIN_STARGATE),
+    (TABLE_MANGLE, CHAIN_OUTPUT, CHAIN_STARGATE_OUTPUT),
+    (TABLE_NAT, CHAIN_PREROUTING, CHAIN_STARGATE_NAT),
+    (TABLE_NAT, CHAIN_OUTPUT, CHAIN_STARGATE_NAT_OUTPUT),
+];
+
+/// The table of one of our chains.
+fn chain_table(chain: &str) -> &'static str {
+    CHAINS
+        .iter()
+        .find(|(_, _, c)| *c == chain)
+        .map_or(TABLE_MANGLE, |(table, _, _)| table)
+}
+
+pub fn gen_jump_rule(chain: &str) -> String {
+    format!("-m comment --comment {COMMENT_TAG} -j {chain}")
+}
+
+/// The target of a rule giving packets the return routing mark.
+fn gen_set_mark(routing: &PolicyRouting) -> String {
+    format!(
+        "-j MARK --set-xmark {:#x}/{:#x}",
+        routing.fwmark, routing.fwmark_mask
+    )
+}
+
+pub fn gen_rule_str(target: SocketAddrV4, routing: &PolicyRouting) -> String {
     let target_ip = target.ip().to_string();
     let target_port = target.port();
-    format!("-p tcp -s {target_ip}/32 --sport {target_port} -j MARK --set-xmark 0x1/0xffffffff")
+    format!(
+        "-p tcp -s {target_ip}/32 --sport {target_port} \
+         -m comment --comment {COMMENT_TAG}:return:{target} {}",
+        gen_set_mark(routing)
+    )
+}
+
+/// The mangle rules that route replies on upstream connections marked with `mark` back to us:
+/// the socket mark is saved to the connection on the way out, and replies on such a connection
+/// get the return routing mark.
+pub fn gen_connmark_rules(mark: u32, routing: &PolicyRouting) -> [(&'static str, String); 2] {
+    [
+        (
+            CHAIN_STARGATE_OUTPUT,
+            format!(
+                "-m mark --mark {mark:#x} -m comment --comment {COMMENT_TAG}:connmark:{mark:#x} \
+                 -j CONNMARK --save-mark"
+            ),
+        ),
+        (
+            CHAIN_STARGATE,
+            format!(
+                "-m connmark --mark {mark:#x} \
+                 -m comment --comment {COMMENT_TAG}:connmark:{mark:#x} {}",
+                gen_set_mark(routing)
+            ),
+        ),
+    ]
+}
+
+/// The rules diverting the traffic of `intercept` to its listener, one per destination net and
+/// port: TPROXY rules marking it so that policy routing delivers it locally, or REDIRECT rules
+/// for traffic from other hosts and from this one, except our own upstream connections.
+pub fn gen_intercept_rules(
+    intercept: &Intercept,
+    routing: &PolicyRouting,
+) -> Vec<(&'static str, String)> {
+    let listener = intercept.listener;
+    let ports: Vec<Option<u16>> = match intercept.destinations.ports.as_slice() {
+        [] => vec![None],
+        ports => ports.iter().copied().map(Some).collect(),
+    };
+    let mut rules = vec![];
+    for net in &intercept.destinations.nets {
+        for port in &ports {
+            let (dport, comment) = match port {
+                Some(port) => (format!(" --dport {port}"), format!("{net}:{port}")),
+                None => (String::new(), net.to_string()),
+            };
+            let matches = format!("-p tcp -d {net}{dport}");
+            let comment = format!(
+                "-m comment --comment {COMMENT_TAG}:{}:{listener}:{comment}",
+                intercept.mode.as_str()
+            );
+            match intercept.mode {
+                MappingMode::Tproxy => rules.push((
+                    CHAIN_STARGATE,
+                    format!(
+                        "{matches} {comment} -j TPROXY \
+                         --on-ip {} --on-port {} --tproxy-mark {:#x}/{:#x}",
+                        listener.ip(),
+                        listener.port(),
+                        routing.fwmark,
+                        routing.fwmark_mask
+                    ),
+                )),
+                MappingMode::Redirect => {
+                    let redirect = format!("-j REDIRECT --to-ports {}", listener.port());
+                    let not_ours = match intercept.upstream_mark {
+                        Some(mark) => format!(" -m mark ! --mark {mark:#x}"),
+                        None => String::new(),
+                    };
+                    rules.push((
+                        CHAIN_STARGATE_NAT,
+                        format!("{matches} {comment} {redirect}"),
+                    ));
+                    rules.push((
+                        CHAIN_STARGATE_NAT_OUTPUT,
+                        format!("{matches}{not_ours} {comment} {redirect}"),
+                    ));
+                }
+                MappingMode::Proxy => (),
+            }
+        }
+    }
+    rules
+}
+
+/// Every rule wanted in our chains for `rules`.
+pub fn gen_rules(rules: &Rules, routing: &PolicyRouting) -> Vec<(&'static str, String)> {
+    let connmark_rules = rules
+        .marks
+        .iter()
+        .flat_map(|mark| gen_connmark_rules(*mark, routing));
+    let return_rules = rules
+        .targets
+        .iter()
+        .map(|target| (CHAIN_STARGATE, gen_rule_str(*target, routing)));
+    let intercept_rules = rules
+        .intercepts
+        .iter()
+        .flat_map(|intercept| gen_intercept_rules(intercept, routing));
+    connmark_rules
+        .chain(return_rules)
+        .chain(intercept_rules)
+        .collect()
+}
+
+/// The word following `option` in `rule`.
+fn rule_option<'a>(rule: &'a str, option: &str) -> Option<&'a str> {
+    let mut words = rule.split_whitespace();
+    words.find(|word| *word == option)?;
+    words.next().map(|value| value.trim_matches('"'))
+}
+
+/// The comment a rule is tagged with, which identifies it within its chain.
+fn rule_comment(rule: &str) -> Option<&str> {
+    rule_option(rule, "--comment")
+}
+
+/// What tells a rule apart from others in its chain: its comment, and the mark it sets or
+/// matches, which changes with the configured return routing or upstream mark.
+fn rule_key(rule: &str) -> Option<(&str, Option<&str>)> {
+    let mark = ["--set-xmark", "--tproxy-mark", "--mark"]
+        .iter()
+        .find_map(|option| rule_option(rule, option));
+    Some((rule_comment(rule)?, mark))
+}
+
+/// Whether the policy routing rule and route created by `initial_setup` are already in place.
+#[cfg(target_os = "linux")]
+pub async fn routing_present(routing: &PolicyRouting) -> anyhow::Result<(bool, bool)> {
+    let (connection, handle, _) = rtnetlink::new_connection()?;
+    tokio::spawn(connection);
+    Ok((
+        find_ip_rule(&handle, routing).await?,
+        find_ip_route(&handle, routing).await?,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn new_iptables() -> anyhow::Result<iptables::IPTables> {
+    iptables::new(false).map_err(|e| anyhow!("IPTables Err: {:?}", e))
+}
+
+/// Creates our chains and the jumps to them where missing, returning how many it created.
+#[cfg(target_os = "linux")]
+fn ensure_chains(ipt: &iptables::IPTables) -> anyhow::Result<usize> {
+    let mut created = 0;
+    for (table, parent, chain) in CHAINS {
+        if !ipt
+            .chain_exists(table, chain)
+            .map_err(|e| anyhow!("IPTables Err: {:?}", e))?
+        {
+            tracing::info!("Creating chain {chain} in {table}");
+            ipt.new_chain(table, chain)
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+            created += 1;
+        }
+        let jump = gen_jump_rule(chain);
+        if !ipt
+            .exists(table, parent, &jump)
+            .map_err(|e| anyhow!("IPTables Err: {:?}", e))?
+        {
+            ipt.append(table, parent, &jump)
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+            created += 1;
+        }
+    }
+    Ok(created)
+}
+
+/// Makes our chains hold exactly `rules`, removing rules left behind by a previous run that
+/// didn't get to clean up after itself. Returns how many chains and rules it had to add or
+/// remove.
+#[cfg(target_os = "linux")]
+pub fn reconcile_sync(rules: &Rules, routing: &PolicyRouting) -> anyhow::Result<usize> {
+    let ipt = new_iptables()?;
+    let mut changes = ensure_chains(&ipt)?;
+    let wanted = gen_rules(rules, routing);
+    for (table, _, chain) in CHAINS {
+        let wanted: HashMap<_, &str> = wanted
+            .iter()
+            .filter(|(c, _)| *c == chain)
+            .filter_map(|(_, rule)| Some((rule_key(rule)?, rule.as_str())))
+            .collect();
+        let prefix = format!("-A {chain} ");
+        let mut present = HashSet::new();
+        let listed = ipt
+            .list(table, chain)
+            .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        for rule in listed.iter().filter_map(|rule| rule.strip_prefix(&prefix)) {
+            match rule_key(rule) {
+                Some(key) if wanted.contains_key(&key) && present.insert(key) => (),
+                _ => {
+                    tracing::info!("Removing stale rule '{rule}' from {chain}");
+                    ipt.delete(table, chain, rule)
+                        .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+                    changes += 1;
+                }
+            }
+        }
+        for (key, rule) in wanted {
+            if !present.contains(&key) {
+                tracing::info!("Creating rule '{rule}' in {chain}");
+                ipt.append(table, chain, rule)
+                    .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+                changes += 1;
+            }
+        }
+    }
+    Ok(changes)
 }
 
 #[cfg(target_os = "linux")]
-pub fn add_iptables_return_rule_sync(target: SocketAddrV4) -> anyhow::Result<()> {
-    let rule = gen_rule_str(target);
+pub async fn reconcile(rules: Rules, routing: PolicyRouting) -> anyhow::Result<usize> {
+    tokio::task::spawn_blocking(move || reconcile_sync(&rules, &routing)).await?
+}
+
+/// Removes our chains along with the jumps to them.
+#[cfg(target_os = "linux")]
+pub fn remove_chains_sync() -> anyhow::Result<()> {
+    let ipt = new_iptables()?;
+    for (table, parent, chain) in CHAINS {
+        let jump = gen_jump_rule(chain);
+        while ipt
+            .exists(table, parent, &jump)
+            .map_err(|e| anyhow!("IPTables Err: {:?}", e))?
+        {
+            ipt.delete(table, parent, &jump)
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        }
+        if ipt
+            .chain_exists(table, chain)
+            .map_err(|e| anyhow!("IPTables Err: {:?}", e))?
+        {
+            tracing::info!("Removing chain {chain}");
+            ipt.flush_chain(table, chain)
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+            ipt.delete_chain(table, chain)
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub async fn remove_chains() -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(remove_chains_sync).await?
+}
+
+#[cfg(target_os = "linux")]
+pub fn add_iptables_return_rule_sync(
+    target: SocketAddrV4,
+    routing: &PolicyRouting,
+) -> anyhow::Result<()> {
+    let rule = gen_rule_str(target, routing);
     tracing::info!("Creating rule '{rule}'");
-    let ipt = iptables::new(false).map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
-    let add_result = ipt.append_unique(TABLE_MANGLE, CHAIN_PREROUTING, rule.as_str());
+    let ipt = new_iptables()?;
+    let add_result = ipt.append_unique(TABLE_MANGLE, CHAIN_STARGATE, rule.as_str());
     tracing::info!("{:?}", add_result);
     Ok(())
 }
 
 #[cfg(target_os = "linux")]
-pub async fn add_iptables_return_rule(target: SocketAddrV4) -> anyhow::Result<()> {
-    tokio::task::spawn_blocking(move || add_iptables_return_rule_sync(target)).await??;
+pub async fn add_iptables_return_rule(
+    target: SocketAddrV4,
+    routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || add_iptables_return_rule_sync(target, &routing)).await??;
     Ok(())
 }
 
 #[cfg(target_os = "linux")]
-pub fn del_iptables_return_rule_sync(target: SocketAddrV4) -> anyhow::Result<()> {
-    let rule = gen_rule_str(target);
-    let ipt = iptables::new(false).map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
-    let del_result = ipt.delete(TABLE_MANGLE, CHAIN_PREROUTING, rule.as_str());
+pub async fn add_connmark_rules(mark: u32, routing: PolicyRouting) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let ipt = new_iptables()?;
+        for (chain, rule) in gen_connmark_rules(mark, &routing) {
+            tracing::info!("Creating rule '{rule}' in {chain}");
+            ipt.append_unique(TABLE_MANGLE, chain, rule.as_str())
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(target_os = "linux")]
+pub async fn add_intercept_rules(
+    intercept: Intercept,
+    routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let ipt = new_iptables()?;
+        for (chain, rule) in gen_intercept_rules(&intercept, &routing) {
+            tracing::info!("Creating rule '{rule}' in {chain}");
+            ipt.append_unique(chain_table(chain), chain, rule.as_str())
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(target_os = "linux")]
+pub async fn del_intercept_rules(
+    intercept: Intercept,
+    routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let ipt = new_iptables()?;
+        for (chain, rule) in gen_intercept_rules(&intercept, &routing) {
+            tracing::info!("Removing rule '{rule}' from {chain}");
+            ipt.delete(chain_table(chain), chain, rule.as_str())
+                .map_err(|e| anyhow!("IPTables Err: {:?}", e))?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(target_os = "linux")]
+pub fn del_iptables_return_rule_sync(
+    target: SocketAddrV4,
+    routing: &PolicyRouting,
+) -> anyhow::Result<()> {
+    let rule = gen_rule_str(target, routing);
+    let ipt = new_iptables()?;
+    let del_result = ipt.delete(TABLE_MANGLE, CHAIN_STARGATE, rule.as_str());
     tracing::info!("{:?}", del_result);
     Ok(())
 }
 #[cfg(target_os = "linux")]
-pub async fn del_iptables_return_rule(target: SocketAddrV4) -> anyhow::Result<()> {
-    tokio::task::spawn_blocking(move || del_iptables_return_rule_sync(target)).await??;
+pub async fn del_iptables_return_rule(
+    target: SocketAddrV4,
+    routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || del_iptables_return_rule_sync(target, &routing)).await??;
     Ok(())
 }
 
 #[cfg(not(target_os = "linux"))]
-pub async fn del_iptables_return_rule(_target: SocketAddrV4) -> anyhow::Result<()> {
+pub async fn del_iptables_return_rule(
+    _target: SocketAddrV4,
+    _routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn add_iptables_return_rule(
+    _target: SocketAddrV4,
+    _routing: PolicyRouting,
+) -> anyhow::Result<()> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn add_connmark_rules(_mark: u32, _routing: PolicyRouting) -> anyhow::Result<()> {
     unimplemented!()
 }
 #[cfg(not(target_os = "linux"))]
-pub async fn add_iptables_return_rule(_target: SocketAddrV4) -> anyhow::Result<()> {
+pub async fn add_intercept_rules(
+    _intercept: Intercept,
+    _routing: PolicyRouting,
+) -> anyhow::Result<()> {
     unimplemented!()
 }
 #[cfg(not(target_os = "linux"))]
-pub async fn initial_setup() -> anyhow::Result<()> {
+pub async fn del_intercept_rules(
+    _intercept: Intercept,
+    _routing: PolicyRouting,
+) -> anyhow::Result<()> {
     unimplemented!()
 }
+#[cfg(not(target_os = "linux"))]
+pub async fn reconcile(_rules: Rules, _routing: PolicyRouting) -> anyhow::Result<usize> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn initial_setup(
+    _routing: &PolicyRouting,
+    _created: &mut (bool, bool),
+) -> anyhow::Result<()> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn remove_routing(
+    _routing: &PolicyRouting,
+    _rule: bool,
+    _route: bool,
+) -> anyhow::Result<()> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn routing_conflicts(_routing: &PolicyRouting) -> anyhow::Result<Vec<String>> {
+    anyhow::bail!("policy routing is only supported on Linux")
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn remove_chains() -> anyhow::Result<()> {
+    unimplemented!()
+}
+#[cfg(not(target_os = "linux"))]
+pub async fn routing_present(_routing: &PolicyRouting) -> anyhow::Result<(bool, bool)> {
+    anyhow::bail!("policy routing is only supported on Linux")
+}
+
+/// Appends rules to our chains in the mangle and nat tables with the `iptables` binary.
+pub struct IptablesBackend {
+    pub routing: PolicyRouting,
+}
+
+#[async_trait]
+impl FirewallBackend for IptablesBackend {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn kind(&self) -> FirewallBackendKind {
+        FirewallBackendKind::Iptables
+    }
+
+    async fn add_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()> {
+        add_iptables_return_rule(target, self.routing).await
+    }
+
+    async fn del_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()> {
+        del_iptables_return_rule(target, self.routing).await
+    }
+
+    async fn add_connmark_rules(&self, mark: u32) -> anyhow::Result<()> {
+        add_connmark_rules(mark, self.routing).await
+    }
+
+    async fn add_intercept(&self, intercept: &Intercept) -> anyhow::Result<()> {
+        add_intercept_rules(intercept.clone(), self.routing).await
+    }
+
+    async fn del_intercept(&self, intercept: &Intercept) -> anyhow::Result<()> {
+        del_intercept_rules(intercept.clone(), self.routing).await
+    }
+
+    async fn reconcile(&self, rules: &Rules) -> anyhow::Result<usize> {
+        reconcile(rules.clone(), self.routing).await
+    }
+
+    async fn teardown(&self) -> anyhow::Result<()> {
+        remove_chains().await
+    }
+
+    fn plan(&self, rules: &Rules) -> Vec<Operation> {
+        let mut operations = vec![];
+        for (table, parent, chain) in CHAINS {
+            operations.push(Operation::FirewallChain {
+                table: table.to_string(),
+                chain: chain.to_string(),
+            });
+            operations.push(Operation::FirewallRule {
+                table: table.to_string(),
+                chain: parent.to_string(),
+                rule: gen_jump_rule(chain),
+            });
+        }
+        operations.extend(
+            gen_rules(rules, &self.routing)
+                .into_iter()
+                .map(|(chain, rule)| Operation::FirewallRule {
+                    table: chain_table(chain).to_string(),
+                    chain: chain.to_string(),
+                    rule,
+                }),
+        );
+        operations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DestinationFilter;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn rules_are_tagged() {
+        let tproxy = Intercept {
+            mode: MappingMode::Tproxy,
+            destinations: DestinationFilter {
+                nets: vec!["10.1.0.0/16".parse().unwrap()],
+                ports: vec![80, 443],
+            },
+            listener: "0.0.0.0:3128".parse().unwrap(),
+            upstream_mark: Some(2),
+        };
+        let redirect = Intercept {
+            mode: MappingMode::Redirect,
+            destinations: DestinationFilter {
+                nets: vec!["10.2.0.0/16".parse().unwrap()],
+                ports: vec![],
+            },
+            listener: "0.0.0.0:3129".parse().unwrap(),
+            upstream_mark: Some(2),
+        };
+        let rules = gen_rules(
+            &Rules {
+                marks: BTreeSet::from([2]),
+                targets: BTreeSet::from(["10.0.0.1:80".parse().unwrap()]),
+                intercepts: BTreeSet::from([tproxy, redirect]),
+            },
+            &PolicyRouting::default(),
+        );
+        let comments: Vec<_> = rules
+            .iter()
+            .map(|(chain, rule)| (*chain, rule_comment(rule).unwrap()))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                (CHAIN_STARGATE_OUTPUT, "stargate:connmark:0x2"),
+                (CHAIN_STARGATE, "stargate:connmark:0x2"),
+                (CHAIN_STARGATE, "stargate:return:10.0.0.1:80"),
+                (
+                    CHAIN_STARGATE,
+                    "stargate:tproxy:0.0.0.0:3128:10.1.0.0/16:80"
+                ),
+                (
+                    CHAIN_STARGATE,
+                    "stargate:tproxy:0.0.0.0:3128:10.1.0.0/16:443"
+                ),
+                (
+                    CHAIN_STARGATE_NAT,
+                    "stargate:redirect:0.0.0.0:3129:10.2.0.0/16"
+                ),
+                (
+                    CHAIN_STARGATE_NAT_OUTPUT,
+                    "stargate:redirect:0.0.0.0:3129:10.2.0.0/16"
+                ),
+            ]
+        );
+        // as listed back by `iptables -S`
+        assert_eq!(
+            rule_key(
+                "-s 10.0.0.1/32 -p tcp -m tcp --sport 80 -m comment \
+                 --comment \"stargate:return:10.0.0.1:80\" -j MARK --set-xmark 0x1/0xffffffff"
+            ),
+            rule_key(&rules[2].1)
+        );
+        assert_eq!(
+            rule_key(
+                "-d 10.1.0.0/16 -p tcp -m tcp --dport 443 -m comment \
+                 --comment \"stargate:tproxy:0.0.0.0:3128:10.1.0.0/16:443\" \
+                 -j TPROXY --on-port 3128 --on-ip 0.0.0.0 --tproxy-mark 0x1/0xffffffff"
+            ),
+            rule_key(&rules[4].1)
+        );
+        // our own upstream connections aren't redirected back to us
+        assert_eq!(
+            rules[6].1,
+            "-p tcp -d 10.2.0.0/16 -m mark ! --mark 0x2 \
+             -m comment --comment stargate:redirect:0.0.0.0:3129:10.2.0.0/16 \
+             -j REDIRECT --to-ports 3129"
+        );
+    }
+
+    #[test]
+    fn rule_conflicts() {
+        let routing = PolicyRouting::default();
+        let rule = |priority, lookup, fwmark| ExistingRule {
+            priority,
+            lookup: Some(lookup),
+            fwmark,
+        };
+        // main table, and our own rule
+        assert_eq!(rule_conflict(&routing, &rule(32766, 254, None)), None);
+        assert_eq!(
+            rule_conflict(&routing, &rule(32765, 100, Some((0x1, 0xffffffff)))),
+            None
+        );
+        // someone else's use of mark 1 or table 100
+        assert!(rule_conflict(&routing, &rule(32765, 51820, Some((0x1, 0xff)))).is_some());
+        assert!(rule_conflict(&routing, &rule(32765, 100, Some((0x4, 0xffffffff)))).is_some());
+        assert_eq!(
+            rule_conflict(&routing, &rule(32765, 51820, Some((0x10, 0xf0)))),
+            None
+        );
+        let routing = PolicyRouting {
+            priority: Some(100),
+            ..routing
+        };
+        assert!(rule_conflict(&routing, &rule(100, 254, None)).is_some());
+        assert!(rule_conflict(&routing, &rule(32765, 100, Some((0x1, 0xffffffff)))).is_some());
+    }
+}
@@ -1,9 +1,22 @@
 #[macro_use]
 extern crate cfg_if;
 
+pub mod access_log;
+pub mod addr_watch;
 mod async_proxy;
 pub mod bind_addr;
+pub mod check;
 pub mod config;
+pub mod control_api;
+pub mod firewall;
+pub mod http_config;
 pub mod iptables_setup;
+pub mod logging;
+pub mod metrics;
+pub mod nftables;
+pub mod plan;
 pub mod spawner;
+pub mod status;
+pub mod systemd;
 mod tcp_helper;
+pub mod teardown;
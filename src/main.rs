@@ -1,36 +1,183 @@
 #[macro_use]
 extern crate cfg_if;
 
+mod access_log;
+mod addr_watch;
 mod async_proxy;
 mod bind_addr;
+mod check;
 mod config;
+mod control_api;
+mod firewall;
+mod http_config;
 mod iptables_setup;
+mod logging;
+mod metrics;
+mod nftables;
+mod plan;
 mod spawner;
+mod status;
+mod systemd;
 mod tcp_helper;
+mod teardown;
 
-use crate::config::FileConfigProvider;
-use clap::Parser;
-use std::path::PathBuf;
+use crate::config::{ConfigFormat, ConfigProvider, FileConfigProvider};
+use crate::control_api::ApiConfigProvider;
+use crate::http_config::{HttpConfigProvider, HttpSource};
+use crate::logging::LogFormat;
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 
 /// Standalone runner for Gallium Service Gateway (StarGate)
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to config file
+    /// Path to config file, or to a directory of a base file plus mapping fragments
     #[arg(short, long)]
     config: Option<String>,
+    /// Config file format, instead of detecting it from the file extension
+    #[arg(long, value_enum, global = true)]
+    config_format: Option<ConfigFormat>,
     /// Print bind addresses and exit
     #[arg(long)]
     bind_addr: bool,
     /// Bind to loopback address
     #[arg(long)]
     bind_loopback: bool,
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Address to serve the admin API on, e.g. 127.0.0.1:9101
+    #[arg(long)]
+    admin_addr: Option<SocketAddr>,
+    /// Manage mappings through a control API on this address instead of a config file
+    #[arg(long, conflicts_with = "config")]
+    control_addr: Option<SocketAddr>,
+    /// File to persist mappings managed through the control API to
+    #[arg(long, requires = "control_addr")]
+    control_state: Option<PathBuf>,
+    /// Poll this HTTP(S) URL for the config instead of reading a config file
+    #[arg(long, conflicts_with_all = ["config", "control_addr"])]
+    config_url: Option<reqwest::Url>,
+    /// File holding a bearer token to send with config URL requests
+    #[arg(long, requires = "config_url")]
+    config_url_token_file: Option<PathBuf>,
+    /// How often to poll the config URL, e.g. `30s`
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    config_url_interval: Duration,
+    /// Timeout for each config URL request
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    config_url_timeout: Duration,
+    /// File to cache the last known-good config from the config URL in
+    #[arg(long, requires = "config_url")]
+    config_url_cache: Option<PathBuf>,
+    /// Write a JSON access log line per finished connection to this file, or `-` for stdout
+    #[arg(long)]
+    access_log: Option<String>,
+    /// Log filter directives, e.g. `info,stargate::async_proxy=debug` (defaults to $RUST_LOG)
+    #[arg(long)]
+    log_filter: Option<String>,
+    /// Log output format
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// Write logs to this file instead of stdout
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// File recording the routing and firewall objects created, so that only those are removed
+    /// on exit or by `cleanup`
+    #[arg(long, global = true, default_value = teardown::DEFAULT_STATE_PATH)]
+    state_file: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate a config file, reporting every problem found, and exit non-zero if any
+    Check {
+        /// Path to config file
+        config: PathBuf,
+    },
+    /// Print the network changes a config would make, without applying any of them
+    DryRun {
+        /// Path to config file
+        config: PathBuf,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Remove the policy routing and firewall rules left behind by a run that didn't exit
+    /// cleanly
+    Cleanup,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 #[tokio::main]
 async fn main() {
     let args: Args = Args::parse();
+    if let Err(e) = logging::init(
+        args.log_filter.as_deref(),
+        args.log_format,
+        args.log_file.as_deref(),
+    ) {
+        eprintln!("Error setting up logging: {:?}", e);
+        exit(1);
+    }
+    if let Some(Command::Check { config }) = &args.command {
+        let format = args
+            .config_format
+            .unwrap_or_else(|| ConfigFormat::from_path(config));
+        let problems = if config.is_dir() {
+            check::check_dir(config).await
+        } else {
+            check::check_file(config, format).await
+        };
+        for problem in &problems {
+            println!("error: {problem}");
+        }
+        if !problems.is_empty() {
+            exit(1);
+        }
+        println!("{}: OK", config.display());
+        return;
+    }
+    if let Some(Command::DryRun { config, format }) = &args.command {
+        let config_provider =
+            FileConfigProvider::new(config.clone(), args.config_format, args.bind_loopback);
+        let (config, int_mappings) = match config_provider.read_config().await {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Error reading config: {:#}", e);
+                exit(1);
+            }
+        };
+        let firewall = firewall::backend(config.firewall_backend, config.policy_routing).await;
+        let plan = plan::plan(&int_mappings, &config.policy_routing, firewall.as_ref()).await;
+        match format {
+            OutputFormat::Text => print!("{plan}"),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&plan).unwrap()),
+        }
+        if !plan.conflicts.is_empty() {
+            exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Cleanup) = &args.command {
+        if let Err(e) = teardown::cleanup(&args.state_file).await {
+            tracing::error!("{:#}", e);
+            exit(1);
+        }
+        return;
+    }
     let bind_addresses = match bind_addr::get_bind_addresses(args.bind_loopback).await {
         Ok(addrs) => addrs,
         Err(e) => {
@@ -42,17 +189,133 @@ async fn main() {
         println!("{:#?}", bind_addresses);
         return;
     }
-    tracing_subscriber::fmt::init();
-    let config_path = args.config.unwrap();
+    if let Err(e) = systemd::collect_activated_listeners() {
+        tracing::error!("Error reading socket-activated listeners: {:?}", e);
+        exit(1);
+    }
+    teardown::init(args.state_file.clone());
+    if let Some(access_log) = &args.access_log {
+        if let Err(e) = access_log::init(access_log.as_str().into()).await {
+            tracing::error!("Error opening access log: {:?}", e);
+            exit(1);
+        }
+    }
+    if let Some(metrics_addr) = args.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_addr).await {
+                tracing::error!("Error serving metrics: {:?}", e);
+            }
+        });
+    }
+    if let Some(admin_addr) = args.admin_addr {
+        tokio::spawn(async move {
+            if let Err(e) = status::serve(admin_addr).await {
+                tracing::error!("Error serving admin API: {:?}", e);
+            }
+        });
+    }
+
+    if let Some(control_addr) = args.control_addr {
+        tracing::info!("Starting Gallium Service Gateway with control API on {control_addr}");
+        let config_provider =
+            match ApiConfigProvider::new(args.control_state, args.bind_loopback).await {
+                Ok(provider) => provider,
+                Err(e) => {
+                    tracing::error!("Error loading control API state: {:?}", e);
+                    exit(1);
+                }
+            };
+        let api = config_provider.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api.serve(control_addr).await {
+                tracing::error!("Error serving control API: {:?}", e);
+            }
+        });
+        run(config_provider, &args.state_file).await;
+        return;
+    }
+
+    if let Some(config_url) = args.config_url {
+        tracing::info!("Starting Gallium Service Gateway with config URL: {config_url}");
+        let bearer_token = match &args.config_url_token_file {
+            Some(path) => match tokio::fs::read_to_string(path).await {
+                Ok(token) => Some(token.trim().to_string()),
+                Err(e) => {
+                    tracing::error!("Error reading config URL token file: {:?}", e);
+                    exit(1);
+                }
+            },
+            None => None,
+        };
+        let source = HttpSource {
+            format: args
+                .config_format
+                .unwrap_or_else(|| ConfigFormat::from_path(Path::new(config_url.path()))),
+            url: config_url,
+            bearer_token,
+            poll_interval: args.config_url_interval,
+            timeout: args.config_url_timeout,
+            cache_path: args.config_url_cache,
+        };
+        let config_provider = match HttpConfigProvider::new(source, args.bind_loopback).await {
+            Ok(provider) => provider,
+            Err(e) => {
+                tracing::error!("Error setting up config URL provider: {:?}", e);
+                exit(1);
+            }
+        };
+        run(config_provider, &args.state_file).await;
+        return;
+    }
+
+    let Some(config_path) = args.config else {
+        tracing::error!("One of --config, --config-url or --control-addr is required");
+        exit(1);
+    };
     tracing::info!(
         "Starting Gallium Service Gateway with config file: {}",
         config_path
     );
-
     let config_path = PathBuf::from(&config_path);
-    let config_provider = FileConfigProvider {
-        config_path,
-        bind_loopback: args.bind_loopback,
+    let config_provider =
+        FileConfigProvider::new(config_path, args.config_format, args.bind_loopback);
+    run(config_provider, &args.state_file).await;
+}
+
+/// Runs until the config says to exit or a SIGTERM/SIGINT arrives, then removes what was set up.
+async fn run(config_provider: impl ConfigProvider, state_file: &Path) {
+    // whatever a run that didn't exit cleanly left behind goes, whether or not this config needs
+    // it; what it does need is set up afresh
+    if let Err(e) = teardown::cleanup(state_file).await {
+        tracing::warn!("failed to remove what a previous run left behind: {:#}", e);
+    }
+    let result = tokio::select! {
+        result = spawner::start(config_provider) => result,
+        signal = shutdown_signal() => {
+            tracing::info!("received {signal}, shutting down");
+            Ok(())
+        }
     };
-    spawner::start(config_provider).await;
+    if let Err(e) = teardown::cleanup(state_file).await {
+        tracing::error!("{:#}", e);
+    }
+    if let Err(e) = result {
+        tracing::error!("{:#}", e);
+        exit(1);
+    }
+}
+
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (Ok(mut sigterm), Ok(mut sigint)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) else {
+        tracing::warn!("cannot listen for shutdown signals");
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
 }
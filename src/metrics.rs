@@ -0,0 +1,325 @@
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::config::IntMapping;
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections_accepted: IntCounterVec,
+    pub connections_active: IntGaugeVec,
+    pub connections_rejected: IntCounterVec,
+    pub connections_routed: IntCounterVec,
+    pub bytes: IntCounterVec,
+    pub upstream_connect_failures: IntCounterVec,
+    pub upstream_connect_seconds: HistogramVec,
+    pub bind_failures: IntCounterVec,
+    pub config_reloads: IntCounterVec,
+    pub routing_repairs: IntCounterVec,
+}
+
+const MAPPING_LABELS: &[&str] = &["listen", "target"];
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("stargate".to_string()), None).unwrap();
+        let connections_accepted = IntCounterVec::new(
+            Opts::new("connections_accepted_total", "Connections accepted"),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let connections_active = IntGaugeVec::new(
+            Opts::new("connections_active", "Connections currently being proxied"),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let connections_rejected = IntCounterVec::new(
+            Opts::new(
+                "connections_rejected_total",
+                "Connections that failed to be accepted or were not intercepted",
+            ),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let connections_routed = IntCounterVec::new(
+            Opts::new(
+                "connections_routed_total",
+                "Connections proxied, by upstream path (direct, transparent or hairpin)",
+            ),
+            &["listen", "target", "path"],
+        )
+        .unwrap();
+        let bytes = IntCounterVec::new(
+            Opts::new(
+                "bytes_total",
+                "Bytes proxied; 'in' is client to upstream, 'out' is upstream to client",
+            ),
+            &["listen", "target", "direction"],
+        )
+        .unwrap();
+        let upstream_connect_failures = IntCounterVec::new(
+            Opts::new(
+                "upstream_connect_failures_total",
+                "Failed upstream connections",
+            ),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let upstream_connect_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_connect_seconds",
+                "Time taken to establish upstream connections",
+            )
+            .buckets(vec![
+                0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let bind_failures = IntCounterVec::new(
+            Opts::new("bind_failures_total", "Failed attempts to bind a listener"),
+            MAPPING_LABELS,
+        )
+        .unwrap();
+        let config_reloads = IntCounterVec::new(
+            Opts::new(
+                "config_reloads_total",
+                "Config reloads, by result (success or failure)",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let routing_repairs = IntCounterVec::new(
+            Opts::new(
+                "routing_repairs_total",
+                "Missing or stale policy routing and firewall objects put right, by kind \
+                 (ip_rule, ip_route or firewall_rule)",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connections_accepted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_active.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_rejected.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_routed.clone()))
+            .unwrap();
+        registry.register(Box::new(bytes.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_connect_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_connect_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(bind_failures.clone())).unwrap();
+        registry.register(Box::new(config_reloads.clone())).unwrap();
+        registry
+            .register(Box::new(routing_repairs.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            connections_accepted,
+            connections_active,
+            connections_rejected,
+            connections_routed,
+            bytes,
+            upstream_connect_failures,
+            upstream_connect_seconds,
+            bind_failures,
+            config_reloads,
+            routing_repairs,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Label values identifying a mapping, in the order of `MAPPING_LABELS`.
+pub fn mapping_labels(mapping: &IntMapping) -> [String; 2] {
+    [mapping.local_bind.to_string(), mapping.target_label()]
+}
+
+pub fn bytes_counter(labels: &[String; 2], direction: &str) -> IntCounter {
+    metrics()
+        .bytes
+        .with_label_values(&[&labels[0], &labels[1], direction])
+}
+
+/// Tracks a connection in the active connections gauge for as long as it is held.
+pub struct ActiveConnection(IntGauge);
+
+impl ActiveConnection {
+    pub fn new(labels: &[String; 2]) -> ActiveConnection {
+        let gauge = metrics()
+            .connections_active
+            .with_label_values(&[&labels[0], &labels[1]]);
+        gauge.inc();
+        ActiveConnection(gauge)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async {
+            (
+                [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+                metrics().encode(),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("serving metrics on http://{addr}/metrics");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MappingMode, SocketOptions};
+    use crate::status::ProxyStatus;
+    use std::net::SocketAddrV4;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn free_addr() -> SocketAddrV4 {
+        match std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+        {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        }
+    }
+
+    async fn scrape(addr: SocketAddrV4) -> String {
+        for _ in 0..50 {
+            if let Ok(mut stream) = tokio::net::TcpStream::connect(addr).await {
+                stream
+                    .write_all(
+                        b"GET /metrics HTTP/1.1\r\nHost: stargate\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                return response;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("metrics endpoint never came up");
+    }
+
+    #[tokio::test]
+    async fn proxied_connection_is_counted() {
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = match target.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let mapping = IntMapping {
+            name: "metrics".to_string(),
+            local_bind: free_addr(),
+            target_address: Some(target_addr),
+            mode: MappingMode::Proxy,
+            destinations: None,
+            transparent: false,
+            manage_iptables: false,
+            hairpin_net: None,
+            idle_timeout_secs: None,
+            half_close_timeout_secs: 2,
+            drain_timeout_secs: 1,
+            bind_retry_initial_secs: 1,
+            bind_retry_max_secs: 1,
+            socket_options: SocketOptions::default(),
+            upstream_mark: None,
+        };
+        let metrics_addr = free_addr();
+        tokio::spawn(serve(metrics_addr.into()));
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let status = ProxyStatus::register(mapping.local_bind, mapping.target_address, false);
+        let proxy = tokio::spawn(crate::async_proxy::start_proxy(
+            mapping.clone(),
+            cancel.clone(),
+            status.clone(),
+        ));
+        status.bind_result(Duration::from_secs(5)).await.unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(mapping.local_bind)
+            .await
+            .unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        upstream.read_exact(&mut request).await.unwrap();
+        upstream.write_all(b"pong!").await.unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.unwrap();
+
+        let labels = format!(r#"listen="{}",target="{target_addr}""#, mapping.local_bind);
+        let rendered = scrape(metrics_addr).await;
+        for series in [
+            format!("stargate_connections_accepted_total{{{labels}}} 1"),
+            format!("stargate_connections_active{{{labels}}} 1"),
+            format!(r#"stargate_bytes_total{{direction="in",{labels}}} 4"#),
+            format!(r#"stargate_bytes_total{{direction="out",{labels}}} 5"#),
+        ] {
+            assert!(rendered.contains(&series), "{series} not in:\n{rendered}");
+        }
+
+        drop(client);
+        drop(upstream);
+        let inactive = format!("stargate_connections_active{{{labels}}} 0");
+        let mut rendered = String::new();
+        for _ in 0..50 {
+            rendered = scrape(metrics_addr).await;
+            if rendered.contains(&inactive) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(
+            rendered.contains(&inactive),
+            "{inactive} not in:\n{rendered}"
+        );
+
+        cancel.cancel();
+        proxy.await.unwrap().unwrap();
+        status.unregister();
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddrV4;
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::config::{FirewallBackendKind, Intercept, MappingMode, PolicyRouting};
+use crate::firewall::{Changes, FirewallBackend, Rules};
+use crate::plan::Operation;
+
+pub const TABLE: &str = "ip stargate";
+/// Set of `address . port` pairs whose packets get the return routing mark.
+pub const RETURN_SET: &str = "return_targets";
+/// Chains holding the TPROXY rules and the REDIRECT rules for traffic from other hosts and from
+/// this one.
+const INTERCEPT_CHAINS: [&str; 3] = ["intercept", "nat_prerouting", "nat_output"];
+/// Every rule we add is tagged with a comment starting with this.
+const COMMENT_TAG: &str = "stargate";
+
+/// Manages a `stargate` table of its own through `nft`, so nothing else's rules are touched.
+/// Each change is applied as a single `nft -f` batch, which the kernel commits atomically.
+pub struct NftablesBackend {
+    routing: PolicyRouting,
+    ready: OnceCell<()>,
+    /// The intercepts in the `INTERCEPT_CHAINS`, which are rewritten whole on every change since
+    /// nft can only delete a rule by its handle.
+    intercepts: Mutex<BTreeSet<Intercept>>,
+}
+
+impl NftablesBackend {
+    pub fn new(routing: PolicyRouting) -> Self {
+        NftablesBackend {
+            routing,
+            ready: OnceCell::new(),
+            intercepts: Mutex::new(BTreeSet::new()),
+        }
+    }
+}
+
+/// The statement giving a packet the return routing mark, leaving bits outside the mask alone.
+fn set_mark(routing: &PolicyRouting) -> String {
+    if routing.fwmark_mask == u32::MAX {
+        format!("meta mark set {:#x}", routing.fwmark)
+    } else {
+        format!(
+            "meta mark set meta mark and {:#x} or {:#x}",
+            !routing.fwmark_mask, routing.fwmark
+        )
+    }
+}
+
+/// The return routing mark and mask, as they appear in the tags of the rules setting them.
+fn mark_tag(routing: &PolicyRouting) -> String {
+    format!("{:#x}/{:#x}", routing.fwmark, routing.fwmark_mask)
+}
+
+/// Adds `statement` to `chain`, tagged with a comment saying what it is for and which marks it
+/// sets or matches, which is what tells it apart from the others in its chain.
+fn add_rule(chain: &str, statement: String, tag: String) -> String {
+    format!("add rule {TABLE} {chain} {statement} comment \"{COMMENT_TAG}:{tag}\"")
+}
+
+/// The chain and tag of a rule added by `add_rule`.
+fn rule_key(command: &str) -> Option<(&str, &str)> {
+    let rule = command.strip_prefix(&format!("add rule {TABLE} "))?;
+    let (chain, _) = rule.split_once(' ')?;
+    let (_, comment) = rule.rsplit_once(" comment ")?;
+    Some((chain, comment.trim_matches('"')))
+}
+
+/// Creates the table, and empties it of anything left over from a previous run.
+fn setup_batch(routing: &PolicyRouting) -> Vec<String> {
+    vec![
+        format!("add table {TABLE}"),
+        format!(
+            "add chain {TABLE} prerouting \
+             {{ type filter hook prerouting priority mangle; policy accept; }}"
+        ),
+        format!(
+            "add chain {TABLE} output {{ type route hook output priority mangle; policy accept; }}"
+        ),
+        format!(
+            "add chain {TABLE} intercept \
+             {{ type filter hook prerouting priority mangle; policy accept; }}"
+        ),
+        format!(
+            "add chain {TABLE} nat_prerouting \
+             {{ type nat hook prerouting priority dstnat; policy accept; }}"
+        ),
+        format!(
+            "add chain {TABLE} nat_output \
+             {{ type nat hook output priority dstnat; policy accept; }}"
+        ),
+        format!("add set {TABLE} {RETURN_SET} {{ type ipv4_addr . inet_service; }}"),
+        format!("flush chain {TABLE} prerouting"),
+        format!("flush chain {TABLE} output"),
+        format!("flush chain {TABLE} intercept"),
+        format!("flush chain {TABLE} nat_prerouting"),
+        format!("flush chain {TABLE} nat_output"),
+        format!("flush set {TABLE} {RETURN_SET}"),
+        add_rule(
+            "prerouting",
+            format!("ip saddr . tcp sport @{RETURN_SET} {}", set_mark(routing)),
+            format!("return:{}", mark_tag(routing)),
+        ),
+    ]
+}
+
+fn element(target: SocketAddrV4) -> String {
+    format!(
+        "{TABLE} {RETURN_SET} {{ {} . {} }}",
+        target.ip(),
+        target.port()
+    )
+}
+
+/// Sets the table up afresh with `rules`.
+fn full_batch(rules: &Rules, routing: &PolicyRouting) -> Vec<String> {
+    let mut batch = setup_batch(routing);
+    for mark in &rules.marks {
+        batch.extend(connmark_batch(*mark, routing));
+    }
+    batch.extend(
+        rules
+            .targets
+            .iter()
+            .map(|target| format!("add element {}", element(*target))),
+    );
+    batch.extend(
+        rules
+            .intercepts
+            .iter()
+            .flat_map(|intercept| intercept_rules(intercept, routing)),
+    );
+    batch
+}
+
+/// The rules diverting the traffic of `intercept`: a TPROXY rule marking it so that policy
+/// routing delivers it locally, or REDIRECT rules for traffic from other hosts and from this one,
+/// except our own upstream connections.
+fn intercept_rules(intercept: &Intercept, routing: &PolicyRouting) -> Vec<String> {
+    let join = |items: Vec<String>| items.join(", ");
+    let nets = join(
+        intercept
+            .destinations
+            .nets
+            .iter()
+            .map(|n| n.to_string())
+            .collect(),
+    );
+    let ports = match intercept.destinations.ports.as_slice() {
+        [] => "meta l4proto tcp".to_string(),
+        ports => format!(
+            "tcp dport {{ {} }}",
+            join(ports.iter().map(|p| p.to_string()).collect())
+        ),
+    };
+    let matches = format!("ip daddr {{ {nets} }} {ports}");
+    let listener = intercept.listener;
+    match intercept.mode {
+        MappingMode::Tproxy => {
+            let to = if listener.ip().is_unspecified() {
+                format!(":{}", listener.port())
+            } else {
+                listener.to_string()
+            };
+            vec![add_rule(
+                "intercept",
+                format!("{matches} tproxy to {to} {} accept", set_mark(routing)),
+                format!("tproxy:{listener}:{}", mark_tag(routing)),
+            )]
+        }
+        MappingMode::Redirect => {
+            let redirect = format!("redirect to :{}", listener.port());
+            let tag = format!("redirect:{listener}");
+            let (not_ours, output_tag) = match intercept.upstream_mark {
+                Some(mark) => (
+                    format!("meta mark != {mark:#x} "),
+                    format!("{tag}:{mark:#x}"),
+                ),
+                None => (String::new(), tag.clone()),
+            };
+            vec![
+                add_rule("nat_prerouting", format!("{matches} {redirect}"), tag),
+                add_rule(
+                    "nat_output",
+                    format!("{not_ours}{matches} {redirect}"),
+                    output_tag,
+                ),
+            ]
+        }
+        MappingMode::Proxy => vec![],
+    }
+}
+
+/// Replaces the rules in the `INTERCEPT_CHAINS` with those for `intercepts`.
+fn intercept_batch(intercepts: &BTreeSet<Intercept>, routing: &PolicyRouting) -> Vec<String> {
+    let mut batch: Vec<String> = INTERCEPT_CHAINS
+        .iter()
+        .map(|chain| format!("flush chain {TABLE} {chain}"))
+        .collect();
+    batch.extend(
+        intercepts
+            .iter()
+            .flat_map(|intercept| intercept_rules(intercept, routing)),
+    );
+    batch
+}
+
+/// The batch making `changes` to a table holding `intercepts`, and the intercepts it will hold.
+fn changes_batch(
+    changes: &Changes,
+    intercepts: &BTreeSet<Intercept>,
+    routing: &PolicyRouting,
+) -> (Vec<String>, BTreeSet<Intercept>) {
+    // adding an element first makes deleting it succeed whether or not it is there, so that one
+    // already gone doesn't fail the rest of the batch
+    let mut batch: Vec<String> = changes
+        .del_targets
+        .iter()
+        .flat_map(|target| {
+            [
+                format!("add element {}", element(*target)),
+                format!("delete element {}", element(*target)),
+            ]
+        })
+        .collect();
+    batch.extend(
+        changes
+            .add_targets
+            .iter()
+            .map(|target| format!("add element {}", element(*target))),
+    );
+    let wanted: BTreeSet<Intercept> = intercepts
+        .difference(&changes.del_intercepts)
+        .chain(&changes.add_intercepts)
+        .cloned()
+        .collect();
+    if wanted != *intercepts {
+        batch.extend(intercept_batch(&wanted, routing));
+    }
+    (batch, wanted)
+}
+
+fn connmark_batch(mark: u32, routing: &PolicyRouting) -> Vec<String> {
+    vec![
+        add_rule(
+            "output",
+            format!("meta mark {mark:#x} ct mark set meta mark"),
+            format!("connmark:{mark:#x}"),
+        ),
+        add_rule(
+            "prerouting",
+            format!("ct mark {mark:#x} {}", set_mark(routing)),
+            format!("connmark:{mark:#x}:{}", mark_tag(routing)),
+        ),
+    ]
+}
+
+async fn apply(batch: &[String]) -> anyhow::Result<()> {
+    let mut script = batch.join("\n");
+    script.push('\n');
+    tracing::debug!("applying nft batch:\n{script}");
+    let mut child = tokio::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(script.as_bytes()).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// How far our table is from holding `rules`: the number of missing or unexpected set elements,
+/// chains and rules, telling rules apart by their chain and tag.
+async fn drift(rules: &Rules, routing: &PolicyRouting) -> anyhow::Result<usize> {
+    let output = tokio::process::Command::new("nft")
+        .args(["-j", "list", "table", TABLE])
+        .output()
+        .await?;
+    if !output.status.success() {
+        // the whole table is gone
+        return Ok(1);
+    }
+    let listing: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Ok(listing_drift(&listing, rules, routing))
+}
+
+/// `drift` going by the JSON listing of our table.
+fn listing_drift(listing: &serde_json::Value, wanted: &Rules, routing: &PolicyRouting) -> usize {
+    let mut elements = BTreeSet::new();
+    let mut chains = HashSet::new();
+    // how many more of each rule are wanted than there are, by chain and tag
+    let mut rules: HashMap<(String, String), isize> = HashMap::new();
+    for command in full_batch(wanted, routing) {
+        if let Some((chain, tag)) = rule_key(&command) {
+            *rules
+                .entry((chain.to_string(), tag.to_string()))
+                .or_default() += 1;
+        }
+    }
+    for object in listing["nftables"].as_array().into_iter().flatten() {
+        if let Some(chain) = object.get("chain") {
+            chains.insert(chain["name"].as_str().unwrap_or_default().to_string());
+        } else if let Some(rule) = object.get("rule") {
+            let key = [&rule["chain"], &rule["comment"]]
+                .map(|field| field.as_str().unwrap_or_default().to_string());
+            *rules.entry(key.into()).or_default() -= 1;
+        } else if let Some(set) = object.get("set") {
+            if set["name"] != RETURN_SET {
+                continue;
+            }
+            for element in set["elem"].as_array().into_iter().flatten() {
+                let concat = &element["concat"];
+                if let (Some(ip), Some(port)) = (concat[0].as_str(), concat[1].as_u64()) {
+                    if let (Ok(ip), Ok(port)) = (ip.parse(), u16::try_from(port)) {
+                        elements.insert(SocketAddrV4::new(ip, port));
+                    }
+                }
+            }
+        }
+    }
+    let missing_chains = ["prerouting", "output"]
+        .iter()
+        .chain(&INTERCEPT_CHAINS)
+        .filter(|chain| !chains.contains(**chain))
+        .count();
+    wanted.targets.symmetric_difference(&elements).count()
+        + missing_chains
+        + rules.values().map(|n| n.unsigned_abs()).sum::<usize>()
+}
+
+impl NftablesBackend {
+    async fn apply(&self, batch: &[String]) -> anyhow::Result<()> {
+        self.ready
+            .get_or_try_init(|| async {
+                tracing::info!("setting up nftables table {TABLE}");
+                apply(&setup_batch(&self.routing)).await
+            })
+            .await?;
+        apply(batch).await
+    }
+}
+
+#[async_trait]
+impl FirewallBackend for NftablesBackend {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn kind(&self) -> FirewallBackendKind {
+        FirewallBackendKind::Nftables
+    }
+
+    async fn add_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()> {
+        tracing::info!("Adding {target} to {RETURN_SET}");
+        self.apply(&[format!("add element {}", element(target))])
+            .await
+    }
+
+    async fn del_return_rule(&self, target: SocketAddrV4) -> anyhow::Result<()> {
+        tracing::info!("Removing {target} from {RETURN_SET}");
+        self.apply(&[format!("delete element {}", element(target))])
+            .await
+    }
+
+    async fn add_connmark_rules(&self, mark: u32) -> anyhow::Result<()> {
+        tracing::info!("Adding connmark rules for mark {mark:#x}");
+        self.apply(&connmark_batch(mark, &self.routing)).await
+    }
+
+    async fn add_intercept(&self, intercept: &Intercept) -> anyhow::Result<()> {
+        tracing::info!("Adding intercept rule for {}", intercept.listener);
+        let mut intercepts = self.intercepts.lock().await;
+        let mut wanted = intercepts.clone();
+        wanted.insert(intercept.clone());
+        self.apply(&intercept_batch(&wanted, &self.routing)).await?;
+        *intercepts = wanted;
+        Ok(())
+    }
+
+    async fn del_intercept(&self, intercept: &Intercept) -> anyhow::Result<()> {
+        tracing::info!("Removing intercept rule for {}", intercept.listener);
+        let mut intercepts = self.intercepts.lock().await;
+        let mut wanted = intercepts.clone();
+        wanted.remove(intercept);
+        self.apply(&intercept_batch(&wanted, &self.routing)).await?;
+        *intercepts = wanted;
+        Ok(())
+    }
+
+    async fn apply_changes(&self, changes: &Changes) -> anyhow::Result<()> {
+        let mut intercepts = self.intercepts.lock().await;
+        let (batch, wanted) = changes_batch(changes, &intercepts, &self.routing);
+        tracing::info!(
+            "Updating {TABLE}: {} return rule(s) added, {} removed, {} intercept(s) added, {} \
+             removed",
+            changes.add_targets.len(),
+            changes.del_targets.len(),
+            changes.add_intercepts.len(),
+            changes.del_intercepts.len()
+        );
+        self.apply(&batch).await?;
+        *intercepts = wanted;
+        Ok(())
+    }
+
+    async fn reconcile(&self, rules: &Rules) -> anyhow::Result<usize> {
+        let mut intercepts = self.intercepts.lock().await;
+        let drift = drift(rules, &self.routing).await?;
+        if drift > 0 || self.ready.get().is_none() {
+            // flushing and refilling happen in the same transaction, so no packet sees the
+            // table half-built
+            apply(&full_batch(rules, &self.routing)).await?;
+            self.ready.set(()).ok();
+        }
+        intercepts.clone_from(&rules.intercepts);
+        Ok(drift)
+    }
+
+    async fn teardown(&self) -> anyhow::Result<()> {
+        tracing::info!("Removing nftables table {TABLE}");
+        // adding first makes deleting succeed whether or not the table is there
+        apply(&[
+            format!("add table {TABLE}"),
+            format!("delete table {TABLE}"),
+        ])
+        .await
+    }
+
+    fn plan(&self, rules: &Rules) -> Vec<Operation> {
+        full_batch(rules, &self.routing)
+            .into_iter()
+            .map(|command| Operation::NftCommand { command })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DestinationFilter;
+
+    #[test]
+    fn plan_batches() {
+        let tproxy = Intercept {
+            mode: MappingMode::Tproxy,
+            destinations: DestinationFilter {
+                nets: vec!["10.1.0.0/16".parse().unwrap()],
+                ports: vec![80, 443],
+            },
+            listener: "0.0.0.0:3128".parse().unwrap(),
+            upstream_mark: Some(2),
+        };
+        let redirect = Intercept {
+            mode: MappingMode::Redirect,
+            listener: "0.0.0.0:3129".parse().unwrap(),
+            ..tproxy.clone()
+        };
+        let commands: Vec<String> = NftablesBackend::new(PolicyRouting::default())
+            .plan(&Rules {
+                marks: BTreeSet::from([2]),
+                targets: BTreeSet::from(["10.0.0.1:80".parse().unwrap()]),
+                intercepts: BTreeSet::from([tproxy, redirect]),
+            })
+            .iter()
+            .map(|op| op.to_string())
+            .collect();
+        assert_eq!(commands[0], "nft add table ip stargate");
+        assert!(commands.contains(
+            &"nft add rule ip stargate prerouting ct mark 0x2 meta mark set 0x1 \
+              comment \"stargate:connmark:0x2:0x1/0xffffffff\""
+                .to_string()
+        ));
+        assert!(commands
+            .contains(&"nft add element ip stargate return_targets { 10.0.0.1 . 80 }".to_string()));
+        assert!(commands.contains(
+            &"nft add rule ip stargate intercept ip daddr { 10.1.0.0/16 } tcp dport { 80, 443 } \
+              tproxy to :3128 meta mark set 0x1 accept \
+              comment \"stargate:tproxy:0.0.0.0:3128:0x1/0xffffffff\""
+                .to_string()
+        ));
+        assert_eq!(
+            commands.last().unwrap(),
+            "nft add rule ip stargate nat_output meta mark != 0x2 \
+             ip daddr { 10.1.0.0/16 } tcp dport { 80, 443 } redirect to :3129 \
+             comment \"stargate:redirect:0.0.0.0:3129:0x2\""
+        );
+    }
+
+    #[test]
+    fn changes_in_one_batch() {
+        let redirect = Intercept {
+            mode: MappingMode::Redirect,
+            destinations: DestinationFilter {
+                nets: vec!["10.1.0.0/16".parse().unwrap()],
+                ports: vec![80],
+            },
+            listener: "0.0.0.0:3129".parse().unwrap(),
+            upstream_mark: None,
+        };
+        let changes = Changes {
+            add_targets: BTreeSet::from(["10.0.0.1:80".parse().unwrap()]),
+            del_targets: BTreeSet::from(["10.0.0.2:80".parse().unwrap()]),
+            ..Default::default()
+        };
+        let intercepts = BTreeSet::from([redirect.clone()]);
+        let (batch, wanted) = changes_batch(&changes, &intercepts, &PolicyRouting::default());
+        assert_eq!(
+            batch,
+            vec![
+                "add element ip stargate return_targets { 10.0.0.2 . 80 }",
+                "delete element ip stargate return_targets { 10.0.0.2 . 80 }",
+                "add element ip stargate return_targets { 10.0.0.1 . 80 }",
+            ]
+        );
+        assert_eq!(wanted, intercepts);
+
+        let changes = Changes {
+            del_intercepts: intercepts.clone(),
+            ..changes
+        };
+        let (batch, wanted) = changes_batch(&changes, &intercepts, &PolicyRouting::default());
+        // the intercept chains are emptied in the same batch
+        assert_eq!(batch.len(), 6);
+        assert_eq!(batch[5], "flush chain ip stargate nat_output");
+        assert!(wanted.is_empty());
+    }
+
+    #[test]
+    fn drift_from_listing() {
+        // as printed by `nft -j list table ip stargate`, with the connmark rules for mark 2
+        // flushed away
+        let listing: serde_json::Value = serde_json::from_str(
+            r#"{"nftables": [
+                {"metainfo": {"version": "1.0.6", "json_schema_version": 1}},
+                {"table": {"family": "ip", "name": "stargate", "handle": 1}},
+                {"chain": {"family": "ip", "table": "stargate", "name": "prerouting",
+                    "handle": 1, "type": "filter", "hook": "prerouting", "prio": -150,
+                    "policy": "accept"}},
+                {"chain": {"family": "ip", "table": "stargate", "name": "output", "handle": 2,
+                    "type": "route", "hook": "output", "prio": -150, "policy": "accept"}},
+                {"set": {"family": "ip", "name": "return_targets", "table": "stargate",
+                    "type": ["ipv4_addr", "inet_service"], "handle": 3,
+                    "elem": [{"concat": ["10.0.0.1", 80]}, {"concat": ["10.0.0.3", 80]}]}},
+                {"rule": {"family": "ip", "table": "stargate", "chain": "prerouting",
+                    "handle": 4, "comment": "stargate:return:0x1/0xffffffff", "expr": []}}
+            ]}"#,
+        )
+        .unwrap();
+        let mut rules = Rules {
+            targets: BTreeSet::from(["10.0.0.1:80".parse().unwrap()]),
+            ..Default::default()
+        };
+        let routing = PolicyRouting::default();
+        // the intercept and nat chains are missing too
+        assert_eq!(listing_drift(&listing, &rules, &routing), 4);
+        rules.marks.insert(2);
+        assert_eq!(listing_drift(&listing, &rules, &routing), 6);
+        // the return rule sets a mark no longer configured, so it is stale and another is missing
+        let routing = PolicyRouting {
+            fwmark: 0x2,
+            ..routing
+        };
+        assert_eq!(listing_drift(&listing, &rules, &routing), 8);
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddrV4;
+
+use ipnet::Ipv4Net;
+use serde::Serialize;
+
+use crate::config::IntMapping;
+use crate::config::PolicyRouting;
+use crate::firewall::{self, FirewallBackend};
+use crate::iptables_setup;
+
+/// A change Stargate would make to the host when started with a given config.
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Operation {
+    IpRule {
+        fwmark: u32,
+        fwmark_mask: u32,
+        table: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        priority: Option<u32>,
+        /// Whether the rule already exists, if that could be determined.
+        present: Option<bool>,
+    },
+    IpRoute {
+        table: u32,
+        present: Option<bool>,
+    },
+    FirewallChain {
+        table: String,
+        chain: String,
+    },
+    FirewallRule {
+        table: String,
+        chain: String,
+        rule: String,
+    },
+    NftCommand {
+        command: String,
+    },
+    Listener {
+        name: String,
+        bind: SocketAddrV4,
+        /// The target address, or the mode of intercepting mappings.
+        target: String,
+        transparent: bool,
+        hairpin_net: Option<Ipv4Net>,
+    },
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let present = |present: &Option<bool>| match present {
+            Some(true) => " (already present)",
+            _ => "",
+        };
+        match self {
+            Operation::IpRule {
+                fwmark,
+                fwmark_mask,
+                table,
+                priority,
+                present: p,
+            } => {
+                if let Some(priority) = priority {
+                    write!(f, "ip rule add priority {priority} ")?;
+                } else {
+                    write!(f, "ip rule add ")?;
+                }
+                write!(
+                    f,
+                    "fwmark {fwmark:#x}/{fwmark_mask:#x} lookup {table}{}",
+                    present(p)
+                )
+            }
+            Operation::IpRoute { table, present: p } => write!(
+                f,
+                "ip route add local 0.0.0.0/0 dev lo table {table}{}",
+                present(p)
+            ),
+            Operation::FirewallChain { table, chain } => {
+                write!(f, "iptables -t {table} -N {chain}")
+            }
+            Operation::FirewallRule { table, chain, rule } => {
+                write!(f, "iptables -t {table} -A {chain} {rule}")
+            }
+            Operation::NftCommand { command } => write!(f, "nft {command}"),
+            Operation::Listener {
+                name,
+                bind,
+                target,
+                transparent,
+                hairpin_net,
+            } => {
+                write!(f, "listen {bind} -> {target} [{name}]")?;
+                if *transparent {
+                    write!(f, " transparent")?;
+                }
+                if let Some(hairpin_net) = hairpin_net {
+                    write!(f, " hairpin {hairpin_net}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+    /// Existing host configuration that would stop the policy routing being set up.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for operation in &self.operations {
+            writeln!(f, "{operation}")?;
+        }
+        for conflict in &self.conflicts {
+            writeln!(f, "conflict: {conflict}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Works out the network changes that starting with `config` would make, without applying them.
+pub async fn plan(
+    int_mappings: &[IntMapping],
+    routing: &PolicyRouting,
+    firewall: &dyn FirewallBackend,
+) -> Plan {
+    let mut operations = vec![];
+    let mut conflicts = vec![];
+    if int_mappings.iter().any(|m| m.is_managed() && m.transparent) {
+        let (rule_present, route_present) = match iptables_setup::routing_present(routing).await {
+            Ok((rule, route)) => (Some(rule), Some(route)),
+            Err(e) => {
+                tracing::warn!("cannot inspect existing policy routing: {:#}", e);
+                (None, None)
+            }
+        };
+        match iptables_setup::routing_conflicts(routing).await {
+            Ok(found) => conflicts = found,
+            Err(e) => tracing::warn!("cannot check for policy routing conflicts: {:#}", e),
+        }
+        operations.push(Operation::IpRule {
+            fwmark: routing.fwmark,
+            fwmark_mask: routing.fwmark_mask,
+            table: routing.table,
+            priority: routing.priority,
+            present: rule_present,
+        });
+        operations.push(Operation::IpRoute {
+            table: routing.table,
+            present: route_present,
+        });
+    }
+    if int_mappings.iter().any(|m| m.is_managed()) {
+        operations.extend(firewall.plan(&firewall::wanted_rules(int_mappings)));
+    }
+    for mapping in int_mappings {
+        operations.push(Operation::Listener {
+            name: mapping.name.clone(),
+            bind: mapping.local_bind,
+            target: mapping.target_label(),
+            transparent: mapping.transparent,
+            hairpin_net: mapping.hairpin_net,
+        });
+    }
+    Plan {
+        operations,
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DestinationFilter, MappingMode, SocketOptions};
+    use crate::iptables_setup::IptablesBackend;
+    use crate::nftables::NftablesBackend;
+
+    fn mapping(name: &str, local_bind: &str, mode: MappingMode) -> IntMapping {
+        IntMapping {
+            name: name.to_string(),
+            local_bind: local_bind.parse().unwrap(),
+            target_address: None,
+            mode,
+            destinations: None,
+            transparent: false,
+            manage_iptables: true,
+            hairpin_net: None,
+            idle_timeout_secs: None,
+            half_close_timeout_secs: 2,
+            drain_timeout_secs: 30,
+            bind_retry_initial_secs: 1,
+            bind_retry_max_secs: 60,
+            socket_options: SocketOptions::default(),
+            upstream_mark: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn operations_for_both_backends() {
+        let destinations = DestinationFilter {
+            nets: vec!["10.1.0.0/16".parse().unwrap()],
+            ports: vec![80],
+        };
+        let mappings = [
+            IntMapping {
+                target_address: Some("10.0.0.1:80".parse().unwrap()),
+                transparent: true,
+                ..mapping("web", "192.0.2.1:8080", MappingMode::Proxy)
+            },
+            IntMapping {
+                destinations: Some(destinations.clone()),
+                transparent: true,
+                ..mapping("tproxy", "0.0.0.0:3128", MappingMode::Tproxy)
+            },
+            IntMapping {
+                destinations: Some(destinations),
+                ..mapping("intercept", "0.0.0.0:3129", MappingMode::Redirect)
+            },
+        ];
+        // a table nothing else uses, so none of it is already present
+        let routing = PolicyRouting {
+            table: 4242,
+            ..PolicyRouting::default()
+        };
+        let routing_ops = [
+            "ip rule add fwmark 0x1/0xffffffff lookup 4242",
+            "ip route add local 0.0.0.0/0 dev lo table 4242",
+        ];
+        let listeners = [
+            "listen 192.0.2.1:8080 -> 10.0.0.1:80 [web] transparent",
+            "listen 0.0.0.0:3128 -> tproxy [tproxy] transparent",
+            "listen 0.0.0.0:3129 -> redirect [intercept]",
+        ];
+        let lines = |plan: Plan| -> Vec<String> {
+            assert!(plan.conflicts.is_empty(), "{:?}", plan.conflicts);
+            plan.operations.iter().map(|op| op.to_string()).collect()
+        };
+        let iptables = lines(plan(&mappings, &routing, &IptablesBackend { routing }).await);
+        let filter = "-p tcp -d 10.1.0.0/16 --dport 80 -m comment --comment";
+        let redirect = format!(
+            "{filter} stargate:redirect:0.0.0.0:3129:10.1.0.0/16:80 -j REDIRECT --to-ports 3129"
+        );
+        let mut expected: Vec<String> = routing_ops.iter().map(|l| l.to_string()).collect();
+        for (table, parent, chain) in iptables_setup::CHAINS {
+            expected.push(format!("iptables -t {table} -N {chain}"));
+            expected.push(format!(
+                "iptables -t {table} -A {parent} -m comment --comment stargate -j {chain}"
+            ));
+        }
+        expected.extend([
+            "iptables -t mangle -A STARGATE -p tcp -s 10.0.0.1/32 --sport 80 -m comment \
+             --comment stargate:return:10.0.0.1:80 -j MARK --set-xmark 0x1/0xffffffff"
+                .to_string(),
+            format!(
+                "iptables -t mangle -A STARGATE {filter} \
+                 stargate:tproxy:0.0.0.0:3128:10.1.0.0/16:80 -j TPROXY \
+                 --on-ip 0.0.0.0 --on-port 3128 --tproxy-mark 0x1/0xffffffff"
+            ),
+            format!("iptables -t nat -A STARGATE_NAT {redirect}"),
+            format!("iptables -t nat -A STARGATE_NAT_OUTPUT {redirect}"),
+        ]);
+        expected.extend(listeners.iter().map(|l| l.to_string()));
+        assert_eq!(iptables, expected);
+
+        let nftables = lines(plan(&mappings, &routing, &NftablesBackend::new(routing)).await);
+        let redirect = "ip daddr { 10.1.0.0/16 } tcp dport { 80 } redirect to :3129 \
+                        comment \"stargate:redirect:0.0.0.0:3129\"";
+        let mut expected: Vec<String> = routing_ops.iter().map(|l| l.to_string()).collect();
+        expected.extend(
+            [
+                "add table ip stargate",
+                "add chain ip stargate prerouting \
+                 { type filter hook prerouting priority mangle; policy accept; }",
+                "add chain ip stargate output \
+                 { type route hook output priority mangle; policy accept; }",
+                "add chain ip stargate intercept \
+                 { type filter hook prerouting priority mangle; policy accept; }",
+                "add chain ip stargate nat_prerouting \
+                 { type nat hook prerouting priority dstnat; policy accept; }",
+                "add chain ip stargate nat_output \
+                 { type nat hook output priority dstnat; policy accept; }",
+                "add set ip stargate return_targets { type ipv4_addr . inet_service; }",
+                "flush chain ip stargate prerouting",
+                "flush chain ip stargate output",
+                "flush chain ip stargate intercept",
+                "flush chain ip stargate nat_prerouting",
+                "flush chain ip stargate nat_output",
+                "flush set ip stargate return_targets",
+                "add rule ip stargate prerouting ip saddr . tcp sport @return_targets \
+                 meta mark set 0x1 comment \"stargate:return:0x1/0xffffffff\"",
+                "add element ip stargate return_targets { 10.0.0.1 . 80 }",
+                "add rule ip stargate intercept ip daddr { 10.1.0.0/16 } tcp dport { 80 } \
+                 tproxy to :3128 meta mark set 0x1 accept \
+                 comment \"stargate:tproxy:0.0.0.0:3128:0x1/0xffffffff\"",
+                &format!("add rule ip stargate nat_prerouting {redirect}"),
+                &format!("add rule ip stargate nat_output {redirect}"),
+            ]
+            .iter()
+            .map(|command| format!("nft {command}")),
+        );
+        expected.extend(listeners.iter().map(|l| l.to_string()));
+        assert_eq!(nftables, expected);
+
+        // nothing to set up for unmanaged mappings besides the listeners
+        let unmanaged: Vec<IntMapping> = mappings
+            .iter()
+            .map(|m| IntMapping {
+                manage_iptables: false,
+                ..m.clone()
+            })
+            .collect();
+        assert_eq!(
+            lines(plan(&unmanaged, &routing, &IptablesBackend { routing }).await),
+            listeners
+        );
+    }
+}
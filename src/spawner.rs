@@ -1,82 +1,557 @@
-use crate::config::ConfigProvider;
-use crate::{async_proxy, iptables_setup};
-use std::collections::{HashMap, HashSet};
+use crate::config::{
+    ConfigProvider, FirewallBackendKind, IntMapping, Intercept, PolicyRouting,
+    DEFAULT_BIND_RETRY_MAX_SECS, DEFAULT_RECONCILE_INTERVAL_SECS,
+};
+use crate::firewall::{self, Changes, FirewallBackend, Rules};
+use crate::metrics::metrics;
+use crate::status::ProxyStatus;
+use crate::teardown::{self, RoutingObjectKind};
+use crate::{addr_watch, async_proxy, iptables_setup, systemd};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::net::SocketAddrV4;
+use std::sync::Arc;
 
-use std::time::Duration;
-use tokio::task::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify, OnceCell};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::Instrument;
 
 struct RunningProxy {
     handle: JoinHandle<anyhow::Result<()>>,
-    target: SocketAddrV4,
-    managed: bool,
+    mapping: IntMapping,
     cancel: tokio_util::sync::CancellationToken,
+    status: Arc<ProxyStatus>,
 }
-pub async fn start(config_provider: impl ConfigProvider) {
-    let mut termination_tasks = tokio::task::JoinSet::new();
-    let (mut config, mut int_mappings) = config_provider.read_config().await.unwrap();
 
-    if config.transparent && config.manage_iptables {
-        iptables_setup::initial_setup().await.unwrap();
+/// Counts the proxies using the return rule for each target and each intercept, so that a rule
+/// shared by several mappings, or by a proxy and its replacement, stays in place until the last
+/// of them has finished draining. The rules to add or remove are queued and applied together by
+/// `commit`.
+#[derive(Clone)]
+struct FirewallRules {
+    kind: FirewallBackendKind,
+    routing: PolicyRouting,
+    /// Only picked, which for `FirewallBackendKind::Auto` means running `nft` and `iptables`, once
+    /// a managed mapping needs it.
+    firewall: Arc<OnceCell<Arc<dyn FirewallBackend>>>,
+    users: Arc<Mutex<HashMap<SocketAddrV4, usize>>>,
+    intercept_users: Arc<Mutex<HashMap<Intercept, usize>>>,
+    pending: Arc<Mutex<Changes>>,
+}
+
+impl FirewallRules {
+    fn new(kind: FirewallBackendKind, routing: PolicyRouting) -> Self {
+        FirewallRules {
+            kind,
+            routing,
+            firewall: Default::default(),
+            users: Default::default(),
+            intercept_users: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    async fn backend(&self) -> Arc<dyn FirewallBackend> {
+        self.firewall
+            .get_or_init(|| firewall::backend(self.kind, self.routing))
+            .await
+            .clone()
+    }
+
+    async fn acquire(&self, target: SocketAddrV4) {
+        let mut users = self.users.lock().await;
+        let count = users.entry(target).or_insert(0);
+        if *count == 0 {
+            let mut pending = self.pending.lock().await;
+            if !pending.del_targets.remove(&target) {
+                pending.add_targets.insert(target);
+            }
+        }
+        *count += 1;
+    }
+
+    /// Has the firewall hold exactly the return rules and intercepts in use and the connmark
+    /// rules for `marks`, returning how many rules were missing or stale.
+    async fn reconcile(&self, marks: &BTreeSet<u32>) -> anyhow::Result<usize> {
+        let users = self.users.lock().await;
+        let intercept_users = self.intercept_users.lock().await;
+        let rules = Rules {
+            marks: marks.clone(),
+            targets: users.keys().copied().collect(),
+            intercepts: intercept_users.keys().cloned().collect(),
+        };
+        let mut pending = self.pending.lock().await;
+        let drift = self.backend().await.reconcile(&rules).await?;
+        // whatever was queued is part of the rules now
+        *pending = Changes::default();
+        Ok(drift)
+    }
+
+    async fn acquire_intercept(&self, intercept: Intercept) {
+        let mut users = self.intercept_users.lock().await;
+        if !users.contains_key(&intercept) {
+            let mut pending = self.pending.lock().await;
+            if !pending.del_intercepts.remove(&intercept) {
+                pending.add_intercepts.insert(intercept.clone());
+            }
+        }
+        *users.entry(intercept).or_insert(0) += 1;
+    }
+
+    async fn release_intercept(&self, intercept: Intercept) {
+        let mut users = self.intercept_users.lock().await;
+        if let Some(count) = users.get_mut(&intercept) {
+            *count -= 1;
+            if *count == 0 {
+                users.remove(&intercept);
+                let mut pending = self.pending.lock().await;
+                if !pending.add_intercepts.remove(&intercept) {
+                    pending.del_intercepts.insert(intercept);
+                }
+            }
+        }
+    }
+
+    async fn release(&self, target: SocketAddrV4) {
+        let mut users = self.users.lock().await;
+        if let Some(count) = users.get_mut(&target) {
+            *count -= 1;
+            if *count == 0 {
+                users.remove(&target);
+                let mut pending = self.pending.lock().await;
+                if !pending.add_targets.remove(&target) {
+                    pending.del_targets.insert(target);
+                }
+            }
+        }
+    }
+
+    /// Applies the queued additions and removals, which `nftables` does in a single batch.
+    async fn commit(&self) {
+        let changes = std::mem::take(&mut *self.pending.lock().await);
+        if changes.is_empty() {
+            return;
+        }
+        if let Err(e) = self.backend().await.apply_changes(&changes).await {
+            tracing::warn!("failed to update firewall rules: {:#}", e);
+        }
+    }
+}
+
+/// Stops `instance` accepting connections, and once its connections have drained releases its
+/// return rule. When it is being replaced, it keeps accepting until the replacement is bound so
+/// that no connection is refused in between, unless it had to close its listener already.
+fn retire(
+    termination_tasks: &mut JoinSet<()>,
+    instance: RunningProxy,
+    firewall_rules: &FirewallRules,
+    replacement: Option<Arc<ProxyStatus>>,
+) {
+    let firewall_rules = firewall_rules.clone();
+    termination_tasks.spawn(async move {
+        if let Some(replacement) = replacement {
+            if let Err(e) = replacement.bind_result(Duration::from_secs(5)).await {
+                tracing::warn!(
+                    "replacement for {} not bound: {}",
+                    instance.mapping.local_bind,
+                    e
+                );
+            }
+        }
+        instance.cancel.cancel();
+        let _ = instance.handle.await.unwrap();
+        if let Some(target) = instance.mapping.return_rule_target() {
+            firewall_rules.release(target).await;
+        }
+        if let Some(intercept) = instance.mapping.intercept() {
+            if instance.mapping.is_managed() {
+                firewall_rules.release_intercept(intercept).await;
+            }
+        }
+        instance.status.unregister();
+    });
+}
+
+/// Adds whichever of the policy routing rule and route are missing, recording the ones it adds
+/// so that they are removed on exit, even if adding the other one fails. Returns which of the two
+/// it added.
+async fn ensure_routing(routing: &PolicyRouting) -> anyhow::Result<(bool, bool)> {
+    let mut created = (false, false);
+    let result = iptables_setup::initial_setup(routing, &mut created).await;
+    let (rule, route) = created;
+    if result.is_ok() || rule || route {
+        let recorded = teardown::record(|state| {
+            if rule {
+                state.add_routing(RoutingObjectKind::Rule, *routing);
+            }
+            if route {
+                state.add_routing(RoutingObjectKind::Route, *routing);
+            }
+        });
+        if let Err(e) = recorded.await {
+            tracing::warn!("failed to record created objects: {:#}", e);
+        }
     }
+    result.map(|()| created)
+}
+
+/// Puts back policy routing, if it was set up, and firewall rules that have gone missing since
+/// they were set up, e.g. after an `iptables -F` or a network restart, and removes stale ones.
+async fn repair(
+    routing: Option<&PolicyRouting>,
+    firewall_rules: &FirewallRules,
+    marks: &BTreeSet<u32>,
+) {
+    if let Some(routing) = routing {
+        match ensure_routing(routing).await {
+            Ok((rule, route)) => {
+                for (repaired, kind) in [(rule, "ip_rule"), (route, "ip_route")] {
+                    if repaired {
+                        tracing::warn!("{kind} had gone missing, added it back");
+                        metrics().routing_repairs.with_label_values(&[kind]).inc();
+                    }
+                }
+            }
+            Err(e) => tracing::error!("failed to repair policy routing: {:#}", e),
+        }
+    }
+    match firewall_rules.reconcile(marks).await {
+        Ok(0) => (),
+        Ok(repaired) => {
+            tracing::warn!("{repaired} firewall rule(s) were missing or stale, repaired them");
+            metrics()
+                .routing_repairs
+                .with_label_values(&["firewall_rule"])
+                .inc_by(repaired as u64);
+        }
+        Err(e) => tracing::error!("failed to repair firewall rules: {:#}", e),
+    }
+}
+
+pub async fn start(config_provider: impl ConfigProvider) -> anyhow::Result<()> {
+    let mut termination_tasks = JoinSet::new();
+    let (mut config, mut int_mappings) = config_provider
+        .read_config()
+        .await
+        .map_err(|e| e.context("failed to read initial config"))?;
+    let routing = config.policy_routing;
+    let firewall_rules = FirewallRules::new(config.firewall_backend, routing);
+    // in strict bind mode, the config to go back to if a new one can't be bound, and the last
+    // config rejected for that reason together with when to try it again
+    let mut previous: Option<(crate::config::Config, Vec<IntMapping>)> = None;
+    let mut rejected: Option<(crate::config::Config, Instant)> = None;
 
+    // the firewall rules are only needed once there is a managed mapping, and policy routing once
+    // there is a managed transparent one; REDIRECT replies come back to our own address
+    let mut firewall_ready = false;
+    let mut routing_ready = false;
+    let mut last_repair = Instant::now();
+    // upstream marks whose connmark rules are in place
+    let mut connmark_rules: BTreeSet<u32> = BTreeSet::new();
     let mut proxies: HashMap<SocketAddrV4, RunningProxy> = HashMap::new();
+    let mut notified_ready = false;
+    // the watchdog is fed from its own task, as a pass can block for longer than its interval
+    // waiting on binds, draining listeners or fetching the config
+    let watchdog = systemd::watchdog_interval();
+    let watchdog_stop = tokio_util::sync::CancellationToken::new();
+    let _watchdog_stop = watchdog_stop.clone().drop_guard();
+    let poll_interval = Duration::from_secs(3);
+    let address_changes = match addr_watch::watch() {
+        Ok(changes) => Some(changes),
+        Err(e) => {
+            tracing::warn!(
+                "not watching interface addresses, changes will be noticed on the next poll: {:#}",
+                e
+            );
+            None
+        }
+    };
 
     loop {
         let mut to_delete: HashSet<SocketAddrV4> = HashSet::from_iter(proxies.keys().cloned());
+        // proxies started in this pass, and the ones they replace
+        let mut started: Vec<SocketAddrV4> = vec![];
+        let mut replaced: Vec<RunningProxy> = vec![];
+        // listeners that can't be bound, for strict bind mode
+        let mut failures = vec![];
 
         if !config.should_exit {
+            let mut to_start = vec![];
             for mapping in &int_mappings {
                 to_delete.remove(&mapping.local_bind);
-                if !proxies.contains_key(&mapping.local_bind) {
-                    if config.transparent && config.manage_iptables {
-                        iptables_setup::add_iptables_return_rule(mapping.target_address)
-                            .await
-                            .ok();
+                if let Some(running) = proxies.get(&mapping.local_bind) {
+                    if running.mapping == *mapping {
+                        continue;
                     }
-                    let cancel = tokio_util::sync::CancellationToken::new();
-                    let handle =
-                        tokio::spawn(async_proxy::start_proxy(mapping.clone(), cancel.clone()));
-                    proxies.insert(
-                        mapping.local_bind,
-                        RunningProxy {
-                            handle,
-                            target: mapping.target_address,
-                            managed: config.transparent && config.manage_iptables,
-                            cancel,
-                        },
+                }
+                if let Some(wildcard) = systemd::shared_wildcard_listener(mapping.local_bind) {
+                    let problem = format!(
+                        "{}: socket activation passed {wildcard}, which only a mapping bound on \
+                         0.0.0.0 is given",
+                        mapping.local_bind
                     );
+                    if config.strict_bind {
+                        failures.push(problem);
+                        continue;
+                    }
+                    tracing::warn!("{problem}, binding once it is closed");
                 }
+                let managed = mapping.is_managed();
+                if managed && mapping.transparent && !routing_ready {
+                    match ensure_routing(&routing).await {
+                        Ok(_) => {
+                            routing_ready = true;
+                            last_repair = Instant::now();
+                        }
+                        Err(e) if !notified_ready => {
+                            return Err(e.context("failed to set up policy routing"));
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                "failed to set up policy routing, not starting {}: {:#}",
+                                mapping.name,
+                                e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                if managed && !firewall_ready {
+                    let rules = firewall::wanted_rules(&int_mappings);
+                    let setup = async {
+                        let firewall = firewall_rules.backend().await;
+                        tracing::info!("using the {} firewall backend", firewall.name());
+                        let kind = firewall.kind();
+                        teardown::record(|state| state.firewall = Some(kind)).await?;
+                        firewall.reconcile(&rules).await
+                    };
+                    match setup.await {
+                        Ok(changed) => {
+                            tracing::info!("firewall rules set up, {changed} rule(s) changed");
+                            connmark_rules.extend(rules.marks);
+                            firewall_ready = true;
+                            last_repair = Instant::now();
+                        }
+                        Err(e) if !notified_ready => {
+                            return Err(e.context("failed to set up firewall rules"));
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                "failed to set up firewall rules, not starting {}: {:#}",
+                                mapping.name,
+                                e
+                            );
+                            continue;
+                        }
+                    }
+                }
+                match mapping.upstream_mark {
+                    // the mark of a plain intercepting mapping only keeps its upstream
+                    // connections from being intercepted, replies come back to our own address
+                    _ if !managed || !mapping.transparent => (),
+                    None => {
+                        if let Some(target) = mapping.return_rule_target() {
+                            firewall_rules.acquire(target).await;
+                        }
+                    }
+                    Some(mark) => {
+                        if !connmark_rules.contains(&mark) {
+                            let firewall = firewall_rules.backend().await;
+                            match firewall.add_connmark_rules(mark).await {
+                                Ok(()) => {
+                                    connmark_rules.insert(mark);
+                                }
+                                Err(e) => tracing::warn!(
+                                    "failed to add connmark rules for mark {mark:#x}: {:#}",
+                                    e
+                                ),
+                            }
+                        }
+                    }
+                }
+                if let Some(intercept) = mapping.intercept() {
+                    if managed {
+                        firewall_rules.acquire_intercept(intercept).await;
+                    }
+                }
+                to_start.push(mapping);
+            }
+            // the rules for the proxies about to start, and those released by the ones that
+            // finished since the last pass, go in together
+            firewall_rules.commit().await;
+            for mapping in to_start {
+                let managed = mapping.is_managed();
+                if let Some(old) = proxies.get(&mapping.local_bind) {
+                    // without SO_REUSEPORT the replacement can't bind while the old listener is
+                    // open, so close it first and leave its connections to drain
+                    if !old.mapping.socket_options.reuses_port()
+                        || !mapping.socket_options.reuses_port()
+                    {
+                        old.cancel.cancel();
+                        if let Err(e) = old.status.listener_closed(Duration::from_secs(5)).await {
+                            tracing::warn!("closing {} to replace it: {}", mapping.local_bind, e);
+                        }
+                    }
+                }
+                let cancel = tokio_util::sync::CancellationToken::new();
+                let status =
+                    ProxyStatus::register(mapping.local_bind, mapping.target_address, managed);
+                let span = tracing::info_span!(
+                    "proxy",
+                    mapping = %mapping.name,
+                    listen = %mapping.local_bind,
+                );
+                let handle = tokio::spawn(
+                    async_proxy::start_proxy(mapping.clone(), cancel.clone(), status.clone())
+                        .instrument(span),
+                );
+                if let Some(old) = proxies.remove(&mapping.local_bind) {
+                    tracing::info!("mapping on {} changed, replacing proxy", mapping.local_bind);
+                    replaced.push(old);
+                }
+                started.push(mapping.local_bind);
+                proxies.insert(
+                    mapping.local_bind,
+                    RunningProxy {
+                        handle,
+                        mapping: mapping.clone(),
+                        cancel,
+                        status,
+                    },
+                );
+            }
+        }
+        if config.strict_bind && !(started.is_empty() && failures.is_empty()) {
+            for local_bind in &started {
+                let status = &proxies[local_bind].status;
+                if let Err(e) = status.bind_result(Duration::from_secs(5)).await {
+                    failures.push(format!("{local_bind}: {e}"));
+                }
+            }
+            if !failures.is_empty() {
+                let failures = failures.join("; ");
+                let Some((previous_config, previous_mappings)) = previous.take() else {
+                    return Err(anyhow::anyhow!("failed to bind listeners: {failures}"));
+                };
+                tracing::error!("rejecting new config, failed to bind listeners: {failures}");
+                metrics()
+                    .config_reloads
+                    .with_label_values(&["failure"])
+                    .inc();
+                for local_bind in &started {
+                    if let Some(instance) = proxies.remove(local_bind) {
+                        retire(&mut termination_tasks, instance, &firewall_rules, None);
+                    }
+                }
+                for old in replaced.drain(..) {
+                    // a proxy that already closed its listener is started again on the next pass
+                    if old.cancel.is_cancelled() {
+                        retire(&mut termination_tasks, old, &firewall_rules, None);
+                    } else {
+                        proxies.insert(old.mapping.local_bind, old);
+                    }
+                }
+                to_delete.clear();
+                let retry_at = Instant::now()
+                    + Duration::from_secs(
+                        config
+                            .bind_retry_max_secs
+                            .unwrap_or(DEFAULT_BIND_RETRY_MAX_SECS),
+                    );
+                rejected = Some((std::mem::replace(&mut config, previous_config), retry_at));
+                int_mappings = previous_mappings;
             }
         }
+        // a new config only counts as reloaded once it has been applied
+        if previous.take().is_some() {
+            metrics()
+                .config_reloads
+                .with_label_values(&["success"])
+                .inc();
+        }
+        // connections to a replaced proxy keep going to its old target until they drain, while
+        // the new one takes over accepting
+        for old in replaced {
+            let replacement = proxies[&old.mapping.local_bind].status.clone();
+            retire(
+                &mut termination_tasks,
+                old,
+                &firewall_rules,
+                Some(replacement),
+            );
+        }
         for port in to_delete {
             if let Some(instance) = proxies.remove(&port) {
                 tracing::info!("dropping task for {port}");
-                termination_tasks.spawn(async move {
-                    instance.cancel.cancel();
-                    let _ = instance.handle.await.unwrap();
-                    if instance.managed {
-                        iptables_setup::del_iptables_return_rule(instance.target)
-                            .await
-                            .ok();
-                    }
-                });
+                retire(&mut termination_tasks, instance, &firewall_rules, None);
             }
         }
+        systemd::release_unused_listeners(proxies.keys().copied());
         if config.should_exit && proxies.is_empty() {
             while !termination_tasks.is_empty() {
                 termination_tasks.join_next().await;
             }
-            return;
+            return Ok(());
         }
-        tokio::time::sleep(Duration::from_secs(3)).await;
-        if let Ok((new_config, new_mappings)) = config_provider.read_config().await {
-            if config != new_config {
-                tracing::info!("new config detected");
-                config = new_config;
-                int_mappings = new_mappings;
+        if !notified_ready {
+            systemd::notify_ready();
+            notified_ready = true;
+            if let Some(interval) = watchdog {
+                tokio::spawn(systemd::feed_watchdog(interval, watchdog_stop.clone()));
             }
         }
+        tokio::select! {
+            _ = tokio::time::sleep(poll_interval) => (),
+            _ = changed(&address_changes) => {
+                tracing::info!("interface addresses changed, re-reading config");
+            }
+        }
+        let repair_interval = config
+            .reconcile_interval_secs
+            .unwrap_or(DEFAULT_RECONCILE_INTERVAL_SECS);
+        if firewall_ready
+            && repair_interval > 0
+            && last_repair.elapsed() >= Duration::from_secs(repair_interval)
+        {
+            repair(
+                routing_ready.then_some(&routing),
+                &firewall_rules,
+                &connmark_rules,
+            )
+            .await;
+            last_repair = Instant::now();
+        }
+        match config_provider.read_config().await {
+            Ok((new_config, new_mappings)) => {
+                let retry_later = match &rejected {
+                    Some((rejected, retry_at)) => {
+                        *rejected == new_config && Instant::now() < *retry_at
+                    }
+                    None => false,
+                };
+                if config != new_config && !retry_later {
+                    tracing::info!("new config detected");
+                    previous = Some((
+                        std::mem::replace(&mut config, new_config),
+                        std::mem::replace(&mut int_mappings, new_mappings),
+                    ));
+                }
+            }
+            Err(e) => {
+                tracing::warn!("failed to read config: {:?}", e);
+                metrics()
+                    .config_reloads
+                    .with_label_values(&["failure"])
+                    .inc();
+            }
+        }
+    }
+}
+
+async fn changed(notify: &Option<Arc<Notify>>) {
+    match notify {
+        Some(notify) => notify.notified().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -88,8 +563,9 @@ mod tests {
 
     pub struct TestTrivialConfigProvider {
         local_port: u16,
-        target_address: String,
+        target_address: std::sync::Arc<std::sync::Mutex<String>>,
         should_exit: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        reuseport: Option<bool>,
     }
 
     #[async_trait::async_trait]
@@ -105,14 +581,25 @@ mod tests {
                 .collect();
             let config = crate::config::Config {
                 mappings: vec![crate::config::Mapping {
+                    name: None,
                     local_port: self.local_port,
-                    target_address: self.target_address.clone(),
+                    target_address: Some(self.target_address.lock().unwrap().clone()),
                     hairpin_net: None,
+                    bind_addrs: None,
+                    transparent: None,
+                    manage_iptables: None,
+                    socket_options: Some(crate::config::SocketOptions {
+                        reuseport: self.reuseport,
+                        ..Default::default()
+                    }),
+                    mode: None,
+                    destinations: None,
                 }],
                 transparent: false,
                 manage_iptables: false,
                 should_exit: self.should_exit.load(std::sync::atomic::Ordering::Relaxed),
                 bind_addrs,
+                ..Default::default()
             };
             let int_mappings = config.to_int_mappings()?;
             Ok((config, int_mappings))
@@ -159,8 +646,9 @@ mod tests {
             std::sync::Arc::new(false.into());
         let config = TestTrivialConfigProvider {
             local_port,
-            target_address: format!("127.0.0.1:{target_port}"),
+            target_address: std::sync::Arc::new(format!("127.0.0.1:{target_port}").into()),
             should_exit: should_exit.clone(),
+            reuseport: None,
         };
 
         let proxy = tokio::spawn(async move { start(config).await });
@@ -223,4 +711,233 @@ mod tests {
             },
         );
     }
+
+    /// Echoes each line back prefixed with `prefix`.
+    async fn prefixed_echo(prefix: &'static str) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (stream_in, mut stream_out) = stream.into_split();
+                    let mut lines = tokio::io::BufReader::new(stream_in).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = format!("{prefix}{line}\n");
+                        if stream_out.write_all(reply.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    async fn send_line(
+        lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+        tx: &mut tokio::net::tcp::OwnedWriteHalf,
+        line: &str,
+    ) -> String {
+        tx.write_all(format!("{line}\n").as_bytes()).await.unwrap();
+        lines.next_line().await.unwrap().unwrap()
+    }
+
+    async fn connect(
+        port: u16,
+    ) -> (
+        tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+        tokio::net::tcp::OwnedWriteHalf,
+    ) {
+        let (rx, tx) = tokio::net::TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap()
+            .into_split();
+        (tokio::io::BufReader::new(rx).lines(), tx)
+    }
+
+    #[tokio::test]
+    async fn changed_target_replaces_proxy() {
+        replaces_proxy(None).await;
+    }
+
+    #[tokio::test]
+    async fn changed_target_replaces_proxy_without_reuseport() {
+        replaces_proxy(Some(false)).await;
+    }
+
+    async fn replaces_proxy(reuseport: Option<bool>) {
+        let old_target = prefixed_echo("old:").await;
+        let new_target = prefixed_echo("new:").await;
+        let local_port = portpicker::pick_unused_port().unwrap();
+        let target_address: std::sync::Arc<std::sync::Mutex<String>> =
+            std::sync::Arc::new(format!("127.0.0.1:{old_target}").into());
+        let should_exit: std::sync::Arc<std::sync::atomic::AtomicBool> =
+            std::sync::Arc::new(false.into());
+        let config = TestTrivialConfigProvider {
+            local_port,
+            target_address: target_address.clone(),
+            should_exit: should_exit.clone(),
+            reuseport,
+        };
+        let proxy = tokio::spawn(async move { start(config).await });
+
+        let (mut old_rx, mut old_tx) = loop {
+            if tokio::net::TcpStream::connect(format!("127.0.0.1:{local_port}"))
+                .await
+                .is_ok()
+            {
+                break connect(local_port).await;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        assert_eq!(send_line(&mut old_rx, &mut old_tx, "a").await, "old:a");
+
+        *target_address.lock().unwrap() = format!("127.0.0.1:{new_target}");
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                // without SO_REUSEPORT, connections are refused or reset while it is replaced
+                if let Ok(stream) =
+                    tokio::net::TcpStream::connect(format!("127.0.0.1:{local_port}")).await
+                {
+                    let (rx, mut tx) = stream.into_split();
+                    let mut rx = tokio::io::BufReader::new(rx).lines();
+                    if tx.write_all(b"b\n").await.is_ok()
+                        && matches!(rx.next_line().await, Ok(Some(line)) if line == "new:b")
+                    {
+                        break;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("new target never reached");
+
+        // the connection made before the change drains to the old target
+        assert_eq!(send_line(&mut old_rx, &mut old_tx, "c").await, "old:c");
+
+        should_exit.store(true, std::sync::atomic::Ordering::Relaxed);
+        drop((old_rx, old_tx));
+        tokio::time::timeout(Duration::from_secs(10), proxy)
+            .await
+            .expect("proxy didn't exit")
+            .unwrap()
+            .unwrap();
+    }
+
+    struct FixedConfigProvider(crate::config::Config);
+
+    #[async_trait::async_trait]
+    impl ConfigProvider for FixedConfigProvider {
+        async fn read_config(
+            &self,
+        ) -> anyhow::Result<(crate::config::Config, Vec<crate::config::IntMapping>)> {
+            let int_mappings = self.0.to_int_mappings()?;
+            Ok((self.0.clone(), int_mappings))
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_bind_fails_startup() {
+        let occupied = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = crate::config::Config {
+            mappings: vec![crate::config::Mapping {
+                name: None,
+                local_port: occupied.local_addr().unwrap().port(),
+                target_address: Some("127.0.0.1:9".to_string()),
+                hairpin_net: None,
+                bind_addrs: None,
+                transparent: None,
+                manage_iptables: None,
+                socket_options: None,
+                mode: None,
+                destinations: None,
+            }],
+            bind_addrs: vec!["127.0.0.1".to_string()],
+            strict_bind: true,
+            ..Default::default()
+        };
+        let result =
+            tokio::time::timeout(Duration::from_secs(10), start(FixedConfigProvider(config)))
+                .await
+                .expect("spawner didn't give up");
+        assert!(result.is_err());
+    }
+
+    /// Serves whatever config was last put in it.
+    struct SharedConfigProvider(std::sync::Arc<std::sync::Mutex<crate::config::Config>>);
+
+    #[async_trait::async_trait]
+    impl ConfigProvider for SharedConfigProvider {
+        async fn read_config(
+            &self,
+        ) -> anyhow::Result<(crate::config::Config, Vec<crate::config::IntMapping>)> {
+            let config = self.0.lock().unwrap().clone();
+            let int_mappings = config.to_int_mappings()?;
+            Ok((config, int_mappings))
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_bind_rejected_reload_counts_as_failure() {
+        let target = prefixed_echo("").await;
+        let local_port = portpicker::pick_unused_port().unwrap();
+        let occupied = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mapping = |local_port: u16| crate::config::Mapping {
+            name: None,
+            local_port,
+            target_address: Some(format!("127.0.0.1:{target}")),
+            hairpin_net: None,
+            bind_addrs: None,
+            transparent: None,
+            manage_iptables: None,
+            socket_options: None,
+            mode: None,
+            destinations: None,
+        };
+        let config = crate::config::Config {
+            mappings: vec![mapping(local_port)],
+            bind_addrs: vec!["127.0.0.1".to_string()],
+            strict_bind: true,
+            ..Default::default()
+        };
+        let shared = std::sync::Arc::new(std::sync::Mutex::new(config.clone()));
+        let proxy = tokio::spawn(start(SharedConfigProvider(shared.clone())));
+        let (mut rx, mut tx) = loop {
+            if tokio::net::TcpStream::connect(format!("127.0.0.1:{local_port}"))
+                .await
+                .is_ok()
+            {
+                break connect(local_port).await;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+
+        let failures = metrics().config_reloads.with_label_values(&["failure"]);
+        let failures_before = failures.get();
+        shared.lock().unwrap().mappings = vec![
+            mapping(local_port),
+            mapping(occupied.local_addr().unwrap().port()),
+        ];
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while failures.get() == failures_before {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("rejected reload never counted");
+        // the config that was already running keeps serving
+        assert_eq!(send_line(&mut rx, &mut tx, "a").await, "a");
+
+        *shared.lock().unwrap() = crate::config::Config {
+            should_exit: true,
+            ..config
+        };
+        drop((rx, tx));
+        tokio::time::timeout(Duration::from_secs(10), proxy)
+            .await
+            .expect("proxy didn't exit")
+            .unwrap()
+            .unwrap();
+    }
 }
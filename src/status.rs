@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyState {
+    Starting,
+    Bound,
+    RetryingBind,
+    Draining,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPath {
+    Direct,
+    Transparent,
+    Hairpin,
+}
+
+impl ConnectionPath {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionPath::Direct => "direct",
+            ConnectionPath::Transparent => "transparent",
+            ConnectionPath::Hairpin => "hairpin",
+        }
+    }
+}
+
+/// Live state of a single proxy listener, shared between the proxy task and the admin API.
+pub struct ProxyStatus {
+    pub local_bind: SocketAddrV4,
+    /// Unset for intercepting mappings.
+    pub target: Option<SocketAddrV4>,
+    pub managed: bool,
+    state: Mutex<ProxyState>,
+    bind_error: Mutex<Option<String>>,
+    bind_failures: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<ConnectionStatus>>>,
+}
+
+pub struct ConnectionStatus {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub upstream: SocketAddr,
+    pub path: ConnectionPath,
+    pub bytes_in: Arc<AtomicU64>,
+    pub bytes_out: Arc<AtomicU64>,
+    pub started: Instant,
+}
+
+/// Removes a connection from its proxy's status when dropped.
+pub struct TrackedConnection {
+    proxy: Arc<ProxyStatus>,
+    pub connection: Arc<ConnectionStatus>,
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.proxy
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.connection.id);
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+static PROXIES: Mutex<Vec<Arc<ProxyStatus>>> = Mutex::new(vec![]);
+
+impl ProxyStatus {
+    /// Creates the status for a new proxy and registers it with the admin API.
+    pub fn register(
+        local_bind: SocketAddrV4,
+        target: Option<SocketAddrV4>,
+        managed: bool,
+    ) -> Arc<Self> {
+        let status = Arc::new(ProxyStatus {
+            local_bind,
+            target,
+            managed,
+            state: Mutex::new(ProxyState::Starting),
+            bind_error: Mutex::new(None),
+            bind_failures: AtomicU64::new(0),
+            connections: Mutex::new(BTreeMap::new()),
+        });
+        PROXIES.lock().unwrap().push(status.clone());
+        status
+    }
+
+    pub fn unregister(self: &Arc<Self>) {
+        PROXIES.lock().unwrap().retain(|p| !Arc::ptr_eq(p, self));
+    }
+
+    pub fn state(&self) -> ProxyState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn set_state(&self, state: ProxyState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Records the outcome of a bind attempt, counting it if it failed.
+    pub fn set_bind_error(&self, error: Option<String>) {
+        if error.is_some() {
+            self.bind_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.bind_error.lock().unwrap() = error;
+    }
+
+    pub fn bind_error(&self) -> Option<String> {
+        self.bind_error.lock().unwrap().clone()
+    }
+
+    /// Waits for the first bind attempt to finish, returning its error if it failed.
+    pub async fn bind_result(&self, timeout: Duration) -> Result<(), String> {
+        let attempt = async {
+            loop {
+                match self.state() {
+                    ProxyState::Bound => return Ok(()),
+                    ProxyState::RetryingBind => {
+                        return Err(self.bind_error().unwrap_or_else(|| "bind failed".into()))
+                    }
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        tokio::time::timeout(timeout, attempt)
+            .await
+            .unwrap_or_else(|_| Err(format!("not bound after {timeout:?}")))
+    }
+
+    /// Waits for a cancelled proxy to close its listener.
+    pub async fn listener_closed(&self, timeout: Duration) -> Result<(), String> {
+        let closed = async {
+            while matches!(self.state(), ProxyState::Starting | ProxyState::Bound) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, closed)
+            .await
+            .map_err(|_| format!("listener still open after {timeout:?}"))
+    }
+
+    pub fn track(
+        self: &Arc<Self>,
+        id: u64,
+        client: SocketAddr,
+        upstream: SocketAddr,
+        path: ConnectionPath,
+    ) -> TrackedConnection {
+        let connection = Arc::new(ConnectionStatus {
+            id,
+            client,
+            upstream,
+            path,
+            bytes_in: Arc::new(AtomicU64::new(0)),
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            started: Instant::now(),
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, connection.clone());
+        TrackedConnection {
+            proxy: self.clone(),
+            connection,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProxySnapshot {
+    pub local_bind: SocketAddrV4,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<SocketAddrV4>,
+    pub managed: bool,
+    pub state: ProxyState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_error: Option<String>,
+    pub bind_failures: u64,
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionSnapshot {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub upstream: SocketAddr,
+    pub path: ConnectionPath,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub age_secs: f64,
+}
+
+pub fn snapshot() -> Vec<ProxySnapshot> {
+    let proxies = PROXIES.lock().unwrap().clone();
+    let mut result: Vec<ProxySnapshot> = proxies
+        .iter()
+        .map(|p| ProxySnapshot {
+            local_bind: p.local_bind,
+            target: p.target,
+            managed: p.managed,
+            state: p.state(),
+            bind_error: p.bind_error(),
+            bind_failures: p.bind_failures.load(Ordering::Relaxed),
+            connections: p
+                .connections
+                .lock()
+                .unwrap()
+                .values()
+                .map(|c| ConnectionSnapshot {
+                    id: c.id,
+                    client: c.client,
+                    upstream: c.upstream,
+                    path: c.path,
+                    bytes_in: c.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: c.bytes_out.load(Ordering::Relaxed),
+                    age_secs: c.started.elapsed().as_secs_f64(),
+                })
+                .collect(),
+        })
+        .collect();
+    result.sort_by_key(|p| p.local_bind);
+    result
+}
+
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new().route("/proxies", get(|| async { Json(snapshot()) }));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("serving admin API on http://{addr}/proxies");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{IntMapping, MappingMode, SocketOptions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn free_addr() -> SocketAddrV4 {
+        match std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+        {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The `/proxies` entry for `local_bind`.
+    async fn proxy(addr: SocketAddrV4, local_bind: SocketAddrV4) -> serde_json::Value {
+        for _ in 0..50 {
+            if let Ok(mut stream) = tokio::net::TcpStream::connect(addr).await {
+                stream
+                    .write_all(
+                        b"GET /proxies HTTP/1.1\r\nHost: stargate\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                let (_, body) = response.split_once("\r\n\r\n").unwrap();
+                let proxies: Vec<serde_json::Value> = serde_json::from_str(body).unwrap();
+                return proxies
+                    .into_iter()
+                    .find(|p| p["local_bind"] == local_bind.to_string())
+                    .unwrap_or_else(|| panic!("{local_bind} not in:\n{body}"));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("admin API never came up");
+    }
+
+    async fn wait_for_state(status: &ProxyStatus, state: ProxyState) {
+        for _ in 0..50 {
+            if status.state() == state {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("still {:?}, expected {state:?}", status.state());
+    }
+
+    #[tokio::test]
+    async fn proxies_report_state_transitions() {
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = match target.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_bind = match busy.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let mapping = IntMapping {
+            name: "status".to_string(),
+            local_bind,
+            target_address: Some(target_addr),
+            mode: MappingMode::Proxy,
+            destinations: None,
+            transparent: false,
+            manage_iptables: false,
+            hairpin_net: None,
+            idle_timeout_secs: None,
+            half_close_timeout_secs: 2,
+            drain_timeout_secs: 10,
+            bind_retry_initial_secs: 1,
+            bind_retry_max_secs: 1,
+            socket_options: SocketOptions::default(),
+            upstream_mark: None,
+        };
+        let admin_addr = free_addr();
+        tokio::spawn(serve(admin_addr.into()));
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let status = ProxyStatus::register(local_bind, Some(target_addr), false);
+        assert_eq!(status.state(), ProxyState::Starting);
+        assert_eq!(proxy(admin_addr, local_bind).await["state"], "starting");
+        let task = tokio::spawn(crate::async_proxy::start_proxy(
+            mapping,
+            cancel.clone(),
+            status.clone(),
+        ));
+
+        // the port is taken, so the proxy keeps retrying until it's freed
+        wait_for_state(&status, ProxyState::RetryingBind).await;
+        let retrying = proxy(admin_addr, local_bind).await;
+        assert_eq!(retrying["state"], "retrying_bind");
+        assert!(retrying["bind_error"].is_string(), "{retrying}");
+        assert!(
+            retrying["bind_failures"].as_u64().unwrap() >= 1,
+            "{retrying}"
+        );
+        drop(busy);
+        wait_for_state(&status, ProxyState::Bound).await;
+        let bound = proxy(admin_addr, local_bind).await;
+        assert_eq!(bound["state"], "bound");
+        assert!(bound.get("bind_error").is_none(), "{bound}");
+
+        let mut client = tokio::net::TcpStream::connect(local_bind).await.unwrap();
+        let (mut upstream, _) = target.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        upstream.read_exact(&mut request).await.unwrap();
+
+        // an open connection keeps a cancelled proxy draining
+        cancel.cancel();
+        wait_for_state(&status, ProxyState::Draining).await;
+        let draining = proxy(admin_addr, local_bind).await;
+        assert_eq!(draining["state"], "draining");
+        assert_eq!(draining["connections"][0]["bytes_in"], 4, "{draining}");
+
+        drop(client);
+        drop(upstream);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        status.unregister();
+        assert!(snapshot().iter().all(|p| p.local_bind != local_bind));
+    }
+}
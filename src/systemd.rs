@@ -0,0 +1,207 @@
+use std::net::{SocketAddr, SocketAddrV4, TcpListener};
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+use tokio_util::sync::CancellationToken;
+
+static ACTIVATED_LISTENERS: Mutex<Vec<(SocketAddrV4, TcpListener)>> = Mutex::new(Vec::new());
+
+/// Pick up any listening sockets passed to us by systemd socket activation
+/// (`LISTEN_FDS`/`LISTEN_FDNAMES`). This should be called once, early in startup.
+pub fn collect_activated_listeners() -> anyhow::Result<()> {
+    let listeners = listeners_from_fds(sd_notify::listen_fds_with_names(true)?);
+    *ACTIVATED_LISTENERS.lock().unwrap() = listeners;
+    Ok(())
+}
+
+fn listeners_from_fds(
+    fds: impl IntoIterator<Item = (RawFd, String)>,
+) -> Vec<(SocketAddrV4, TcpListener)> {
+    let mut listeners = vec![];
+    for (fd, name) in fds {
+        // SAFETY: systemd hands these fds over to us, and nothing else in the process owns them.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(SocketAddr::V4(addr)) => {
+                tracing::info!("received socket-activated listener '{name}' on {addr}");
+                listeners.push((addr, listener));
+            }
+            Ok(addr) => {
+                tracing::warn!("ignoring socket-activated listener '{name}' on {addr}: not IPv4");
+            }
+            Err(e) => {
+                tracing::warn!("ignoring socket-activated fd {fd} ('{name}'): {:?}", e);
+            }
+        }
+    }
+    listeners
+}
+
+/// The listener bound to exactly `bind_addr`. One on 0.0.0.0, which is what
+/// `ListenStream=<port>` gives, only goes to a mapping bound on 0.0.0.0, since it accepts
+/// connections to every address.
+fn find_listener(
+    listeners: &[(SocketAddrV4, TcpListener)],
+    bind_addr: SocketAddrV4,
+) -> Option<&(SocketAddrV4, TcpListener)> {
+    listeners.iter().find(|(addr, _)| *addr == bind_addr)
+}
+
+/// The listener on 0.0.0.0 holding the port of a per-address `bind_addr` that has no listener
+/// of its own, which the mapping then can't bind until it is closed.
+fn wildcard_in_the_way(
+    listeners: &[(SocketAddrV4, TcpListener)],
+    bind_addr: SocketAddrV4,
+) -> Option<SocketAddrV4> {
+    if bind_addr.ip().is_unspecified() || find_listener(listeners, bind_addr).is_some() {
+        return None;
+    }
+    listeners
+        .iter()
+        .map(|(addr, _)| *addr)
+        .find(|addr| addr.ip().is_unspecified() && addr.port() == bind_addr.port())
+}
+
+/// Returns a socket-activated listener for `bind_addr`, if one was passed in. The original fd
+/// is retained so the listener can be handed out again if the mapping is later re-created,
+/// until `release_unused_listeners` finds it is no longer needed.
+pub fn activated_listener(
+    bind_addr: SocketAddrV4,
+) -> anyhow::Result<Option<tokio::net::TcpListener>> {
+    let listeners = ACTIVATED_LISTENERS.lock().unwrap();
+    match find_listener(&listeners, bind_addr) {
+        Some((_, listener)) => {
+            let listener = listener.try_clone()?;
+            listener.set_nonblocking(true)?;
+            Ok(Some(tokio::net::TcpListener::from_std(listener)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The socket-activated listener on 0.0.0.0 that a mapping bound on `bind_addr` would need to
+/// share, see `wildcard_in_the_way`.
+pub fn shared_wildcard_listener(bind_addr: SocketAddrV4) -> Option<SocketAddrV4> {
+    wildcard_in_the_way(&ACTIVATED_LISTENERS.lock().unwrap(), bind_addr)
+}
+
+/// Closes the socket-activated listeners that none of the proxies on `in_use` would get, so
+/// that the kernel stops queueing connections for mappings that have gone away.
+pub fn release_unused_listeners(in_use: impl IntoIterator<Item = SocketAddrV4>) {
+    let mut listeners = ACTIVATED_LISTENERS.lock().unwrap();
+    if !listeners.is_empty() {
+        retain_used(&mut listeners, in_use);
+    }
+}
+
+fn retain_used(
+    listeners: &mut Vec<(SocketAddrV4, TcpListener)>,
+    in_use: impl IntoIterator<Item = SocketAddrV4>,
+) {
+    let used: Vec<SocketAddrV4> = in_use
+        .into_iter()
+        .filter_map(|bind_addr| find_listener(listeners, bind_addr).map(|(addr, _)| *addr))
+        .collect();
+    listeners.retain(|(addr, _)| {
+        let keep = used.contains(addr);
+        if !keep {
+            tracing::info!("closing socket-activated listener on {addr}, no mapping uses it");
+        }
+        keep
+    });
+}
+
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        tracing::warn!("failed to notify service manager of readiness: {:?}", e);
+    }
+}
+
+pub fn notify_watchdog() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+        tracing::warn!("failed to send watchdog keepalive: {:?}", e);
+    }
+}
+
+/// Sends a watchdog keepalive every half `interval` until `stop` is cancelled.
+pub async fn feed_watchdog(interval: Duration, stop: CancellationToken) {
+    let mut keepalives = tokio::time::interval(interval / 2);
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => return,
+            _ = keepalives.tick() => notify_watchdog(),
+        }
+    }
+}
+
+/// The interval at which the service manager expects watchdog keepalives, if enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn wildcard_listeners_only_go_to_wildcard_binds() {
+        let exact = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = exact.local_addr().unwrap().port();
+        let wildcard = TcpListener::bind("0.0.0.0:0").unwrap();
+        let wildcard_port = wildcard.local_addr().unwrap().port();
+        let not_a_socket = std::fs::File::open("/dev/null").unwrap();
+        let mut listeners = listeners_from_fds([
+            (exact.into_raw_fd(), "exact".to_string()),
+            (not_a_socket.into_raw_fd(), "null".to_string()),
+            (wildcard.into_raw_fd(), "wildcard".to_string()),
+        ]);
+        let addrs: Vec<SocketAddrV4> = listeners.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                SocketAddrV4::new([127, 0, 0, 1].into(), port),
+                SocketAddrV4::new([0, 0, 0, 0].into(), wildcard_port),
+            ]
+        );
+
+        let found = |listeners: &[(SocketAddrV4, TcpListener)], ip: [u8; 4], port| {
+            find_listener(listeners, SocketAddrV4::new(ip.into(), port)).map(|(addr, _)| *addr)
+        };
+        assert_eq!(found(&listeners, [127, 0, 0, 1], port), Some(addrs[0]));
+        assert_eq!(found(&listeners, [127, 0, 0, 2], port), None);
+        assert_eq!(
+            found(&listeners, [0, 0, 0, 0], wildcard_port),
+            Some(addrs[1])
+        );
+        // two mappings on the wildcard's port but different addresses don't get to share it
+        for ip in [[10, 0, 0, 5], [10, 0, 0, 6]] {
+            let bind_addr = SocketAddrV4::new(ip.into(), wildcard_port);
+            assert_eq!(found(&listeners, ip, wildcard_port), None);
+            assert_eq!(wildcard_in_the_way(&listeners, bind_addr), Some(addrs[1]));
+        }
+        assert_eq!(
+            wildcard_in_the_way(&listeners, SocketAddrV4::new([127, 0, 0, 2].into(), port)),
+            None
+        );
+
+        retain_used(
+            &mut listeners,
+            [SocketAddrV4::new([0, 0, 0, 0].into(), wildcard_port)],
+        );
+        assert_eq!(found(&listeners, [127, 0, 0, 1], port), None);
+        assert_eq!(
+            found(&listeners, [0, 0, 0, 0], wildcard_port),
+            Some(addrs[1])
+        );
+        // the released listener's port is free again
+        assert!(TcpListener::bind(addrs[0]).is_ok());
+    }
+}
@@ -1,23 +1,99 @@
-use socket2::{Domain, Socket, Type};
+use crate::config::SocketOptions;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
 use std::net::SocketAddrV4;
 use std::net::TcpListener;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tracing::info;
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
-        use nix::sys::socket::sockopt::{IpTransparent, ReuseAddr};
+        use nix::libc;
+        use nix::sys::socket::sockopt::{IpTransparent, Mark, OriginalDst, ReuseAddr};
         use nix::sys::socket::{AddressFamily, SockFlag, SockProtocol, SockType, SockaddrIn};
-        use std::io;
-        use std::os::fd::AsRawFd;
+        use std::net::Ipv4Addr;
+        use std::os::fd::{AsFd, AsRawFd};
     } else {
     }
 }
 
+const DEFAULT_BACKLOG: u32 = 128;
+
+/// Sets an integer socket option that neither socket2 nor nix wrap.
+#[cfg(target_os = "linux")]
+fn set_int_sockopt(
+    socket: &impl AsFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the fd is valid for the duration of the borrow, and the value pointer and length
+    // describe a c_int
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_fd().as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Applies the per-connection options to a client or upstream socket.
+pub fn apply_stream_options(socket: SockRef, options: &SocketOptions) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        socket.set_nodelay(nodelay)?;
+    }
+    if options.keepalive_enabled() {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(secs) = options.keepalive_idle_secs {
+            keepalive = keepalive.with_time(Duration::from_secs(secs));
+        }
+        if let Some(secs) = options.keepalive_interval_secs {
+            keepalive = keepalive.with_interval(Duration::from_secs(secs));
+        }
+        if let Some(count) = options.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    } else if options.keepalive == Some(false) {
+        socket.set_keepalive(false)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(ms) = options.user_timeout_ms {
+        socket.set_tcp_user_timeout(Some(Duration::from_millis(ms)))?;
+    }
+    Ok(())
+}
+
+/// Applies the options for a socket that is about to connect upstream.
+fn apply_upstream_options(socket: SockRef, options: &SocketOptions) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if options.fastopen.is_some() {
+        set_int_sockopt(&*socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+    }
+    apply_stream_options(socket, options)
+}
+
 #[cfg(target_os = "linux")]
 fn sync_tcp_connect(
     bind_addr: SockaddrIn,
     target_sockaddr: SockaddrIn,
+    options: &SocketOptions,
+    mark: Option<u32>,
 ) -> io::Result<std::net::TcpStream> {
     let sock_fd = nix::sys::socket::socket(
         AddressFamily::Inet,
@@ -28,6 +104,10 @@ fn sync_tcp_connect(
 
     nix::sys::socket::setsockopt(&sock_fd, ReuseAddr, &true)?;
     nix::sys::socket::setsockopt(&sock_fd, IpTransparent, &true)?;
+    if let Some(mark) = mark {
+        nix::sys::socket::setsockopt(&sock_fd, Mark, &mark)?;
+    }
+    apply_upstream_options(SockRef::from(&sock_fd), options)?;
     let raw_fd = sock_fd.as_raw_fd();
 
     nix::sys::socket::bind(raw_fd.clone(), &bind_addr)?;
@@ -40,11 +120,15 @@ fn sync_tcp_connect(
 pub async fn tcpstream_connect_from_addr(
     bind_addr: SocketAddrV4,
     target_addr: SocketAddrV4,
+    options: &SocketOptions,
+    mark: Option<u32>,
 ) -> anyhow::Result<TcpStream> {
     let bind = SockaddrIn::from(bind_addr);
     let target = SockaddrIn::from(target_addr);
+    let options = options.clone();
     let outgoing_std: std::net::TcpStream =
-        tokio::task::spawn_blocking(move || sync_tcp_connect(bind, target)).await??;
+        tokio::task::spawn_blocking(move || sync_tcp_connect(bind, target, &options, mark))
+            .await??;
     Ok(TcpStream::from_std(outgoing_std)?)
 }
 
@@ -52,17 +136,128 @@ pub async fn tcpstream_connect_from_addr(
 pub async fn tcpstream_connect_from_addr(
     _bind_addr: SocketAddrV4,
     _target_addr: SocketAddrV4,
+    _options: &SocketOptions,
+    _mark: Option<u32>,
 ) -> anyhow::Result<TcpStream> {
     unimplemented!()
 }
 
-pub async fn bind_reuseport(bind_addr: SocketAddrV4) -> anyhow::Result<tokio::net::TcpListener> {
+/// Connects to `target` from an address picked by the system.
+pub async fn connect(
+    target: SocketAddrV4,
+    options: &SocketOptions,
+    mark: Option<u32>,
+) -> anyhow::Result<TcpStream> {
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    if let Some(mark) = mark {
+        #[cfg(target_os = "linux")]
+        nix::sys::socket::setsockopt(&socket, Mark, &mark)?;
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("cannot set mark {mark:#x}, SO_MARK is only supported on Linux");
+    }
+    apply_upstream_options(SockRef::from(&socket), options)?;
+    Ok(socket.connect(target.into()).await?)
+}
+
+/// The destination `stream` was connecting to before a REDIRECT rule sent it to us.
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> anyhow::Result<SocketAddrV4> {
+    let addr = nix::sys::socket::getsockopt(stream, OriginalDst)?;
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> anyhow::Result<SocketAddrV4> {
+    anyhow::bail!("SO_ORIGINAL_DST is only supported on Linux")
+}
+
+/// Binds a listener on `bind_addr`. A `transparent` listener also accepts connections diverted to
+/// it by TPROXY rules, which are addressed to other hosts.
+pub async fn bind_listener(
+    bind_addr: SocketAddrV4,
+    options: &SocketOptions,
+    transparent: bool,
+) -> anyhow::Result<tokio::net::TcpListener> {
     let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
     let address = bind_addr.into();
-    socket.set_reuse_port(true)?;
+    // lets a replacement listener bind while connections accepted by the old one drain
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(options.reuses_port())?;
+    #[cfg(target_os = "linux")]
+    if transparent {
+        nix::sys::socket::setsockopt(&socket, IpTransparent, &true)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    if transparent {
+        anyhow::bail!("transparent listeners are only supported on Linux");
+    }
     socket.set_nonblocking(true)?;
+    // accepted sockets inherit the buffer sizes, which need setting before the handshake to
+    // affect window scaling
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
     socket.bind(&address)?;
-    socket.listen(128)?;
+    socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG) as i32)?;
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(secs) = options.defer_accept_secs {
+            set_int_sockopt(
+                &socket,
+                libc::IPPROTO_TCP,
+                libc::TCP_DEFER_ACCEPT,
+                secs as libc::c_int,
+            )?;
+        }
+        if let Some(queue) = options.fastopen {
+            set_int_sockopt(
+                &socket,
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                queue as libc::c_int,
+            )?;
+        }
+    }
     let std_listener: TcpListener = socket.into();
     Ok(tokio::net::TcpListener::from_std(std_listener)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn options_apply_to_both_sides() {
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive_idle_secs: Some(30),
+            recv_buffer_size: Some(65536),
+            ..Default::default()
+        };
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), &options, false)
+            .await
+            .unwrap();
+        let target = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let upstream = connect(target, &options, None).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        apply_stream_options(SockRef::from(&accepted), &options).unwrap();
+
+        for stream in [&upstream, &accepted] {
+            let socket = SockRef::from(stream);
+            assert!(socket.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            // the kernel doubles the requested size for bookkeeping overhead
+            assert!(socket.recv_buffer_size().unwrap() >= 65536);
+        }
+    }
+}
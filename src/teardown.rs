@@ -0,0 +1,217 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::{FirewallBackendKind, PolicyRouting};
+use crate::{firewall, iptables_setup};
+
+pub const DEFAULT_STATE_PATH: &str = "/run/stargate/state.json";
+
+/// The host objects Stargate has created, so that cleaning up removes those and nothing that was
+/// already there. Kept across restarts, so that objects created by a run that was killed are
+/// still cleaned up by a later one.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct State {
+    /// The policy routing rules and routes we created.
+    #[serde(default)]
+    pub routing: Vec<RoutingObject>,
+    /// The backend whose rules we installed.
+    pub firewall: Option<FirewallBackendKind>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingObjectKind {
+    Rule,
+    Route,
+}
+
+/// A policy routing rule or route, with the table, mark and priority it was created with, which
+/// may have been reconfigured since.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RoutingObject {
+    pub kind: RoutingObjectKind,
+    pub routing: PolicyRouting,
+}
+
+impl State {
+    /// Adds a created rule or route, unless it is already recorded.
+    pub fn add_routing(&mut self, kind: RoutingObjectKind, routing: PolicyRouting) {
+        let object = RoutingObject { kind, routing };
+        if !self.routing.contains(&object) {
+            self.routing.push(object);
+        }
+    }
+}
+
+static STATE_PATH: OnceLock<PathBuf> = OnceLock::new();
+static STATE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Sets where `record` keeps the state. Until this is called nothing is recorded.
+pub fn init(path: PathBuf) {
+    let _ = STATE_PATH.set(path);
+}
+
+async fn read(path: &Path) -> anyhow::Result<Option<State>> {
+    if !tokio::fs::try_exists(path).await? {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&tokio::fs::read(path).await?)?))
+}
+
+/// Applies `update` to the recorded state.
+pub async fn record(update: impl FnOnce(&mut State)) -> anyhow::Result<()> {
+    let Some(path) = STATE_PATH.get() else {
+        return Ok(());
+    };
+    record_in(path, update).await
+}
+
+async fn record_in(path: &Path, update: impl FnOnce(&mut State)) -> anyhow::Result<()> {
+    let _guard = STATE_LOCK.lock().await;
+    let mut state = read(path).await?.unwrap_or_default();
+    update(&mut state);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(&state)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// Removes the objects a `State` records.
+#[async_trait]
+trait Remover {
+    async fn remove_firewall(&self, kind: FirewallBackendKind) -> anyhow::Result<()>;
+    async fn remove_routing(&self, object: &RoutingObject) -> anyhow::Result<()>;
+}
+
+struct Host;
+
+#[async_trait]
+impl Remover for Host {
+    async fn remove_firewall(&self, kind: FirewallBackendKind) -> anyhow::Result<()> {
+        // tearing down removes our chains or table whole, whatever they were created with
+        firewall::backend(kind, PolicyRouting::default())
+            .await
+            .teardown()
+            .await
+    }
+
+    async fn remove_routing(&self, object: &RoutingObject) -> anyhow::Result<()> {
+        let rule = object.kind == RoutingObjectKind::Rule;
+        iptables_setup::remove_routing(&object.routing, rule, !rule).await
+    }
+}
+
+/// Removes everything recorded in the state file at `path`, then the file itself. The file is
+/// kept if anything could not be removed, so that cleaning up can be retried.
+pub async fn cleanup(path: &Path) -> anyhow::Result<()> {
+    cleanup_with(path, &Host).await
+}
+
+async fn cleanup_with(path: &Path, remover: &(impl Remover + Sync)) -> anyhow::Result<()> {
+    let _guard = STATE_LOCK.lock().await;
+    let Some(state) = read(path).await? else {
+        tracing::info!("no state in {}, nothing to clean up", path.display());
+        return Ok(());
+    };
+    let mut errors = vec![];
+    if let Some(kind) = state.firewall {
+        if let Err(e) = remover.remove_firewall(kind).await {
+            errors.push(format!("removing firewall rules: {e:#}"));
+        }
+    }
+    for object in &state.routing {
+        if let Err(e) = remover.remove_routing(object).await {
+            errors.push(format!("removing policy routing: {e:#}"));
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("cleanup incomplete: {}", errors.join("; "));
+    }
+    tokio::fs::remove_file(path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records what it is asked to remove, failing for routing objects in `table`.
+    #[derive(Default)]
+    struct FakeRemover {
+        fail_table: Option<u32>,
+        removed: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Remover for FakeRemover {
+        async fn remove_firewall(&self, kind: FirewallBackendKind) -> anyhow::Result<()> {
+            self.removed.lock().unwrap().push(format!("{kind:?}"));
+            Ok(())
+        }
+
+        async fn remove_routing(&self, object: &RoutingObject) -> anyhow::Result<()> {
+            if Some(object.routing.table) == self.fail_table {
+                anyhow::bail!("table {} is busy", object.routing.table);
+            }
+            let removed = format!("{:?} {}", object.kind, object.routing.table);
+            self.removed.lock().unwrap().push(removed);
+            Ok(())
+        }
+    }
+
+    fn routing(table: u32) -> PolicyRouting {
+        PolicyRouting {
+            table,
+            ..PolicyRouting::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_removes_recorded_objects() {
+        let path = std::env::temp_dir().join(format!("stargate-state-{}", std::process::id()));
+        let path = path.join("state.json");
+        record_in(&path, |state| {
+            state.firewall = Some(FirewallBackendKind::Nftables);
+            state.add_routing(RoutingObjectKind::Rule, routing(100));
+            state.add_routing(RoutingObjectKind::Route, routing(100));
+        })
+        .await
+        .unwrap();
+        // a run with different routing settings adds to, rather than replaces, the state
+        record_in(&path, |state| {
+            state.add_routing(RoutingObjectKind::Rule, routing(100));
+            state.add_routing(RoutingObjectKind::Rule, routing(200));
+        })
+        .await
+        .unwrap();
+
+        let failing = FakeRemover {
+            fail_table: Some(200),
+            ..FakeRemover::default()
+        };
+        let e = cleanup_with(&path, &failing).await.unwrap_err();
+        assert!(e.to_string().contains("table 200 is busy"), "{e:#}");
+        assert!(path.exists(), "state file removed after a failed cleanup");
+
+        let remover = FakeRemover::default();
+        cleanup_with(&path, &remover).await.unwrap();
+        assert_eq!(
+            *remover.removed.lock().unwrap(),
+            vec!["Nftables", "Rule 100", "Route 100", "Rule 200"]
+        );
+        assert!(!path.exists());
+
+        // nothing recorded, nothing removed
+        let remover = FakeRemover::default();
+        cleanup_with(&path, &remover).await.unwrap();
+        assert!(remover.removed.lock().unwrap().is_empty());
+        tokio::fs::remove_dir(path.parent().unwrap()).await.unwrap();
+    }
+}